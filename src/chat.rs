@@ -0,0 +1,319 @@
+//! The chat connection the engine runs on, split into a `ChatSink` it talks through and a
+//! `ChatSource` of the events it reacts to. `twitch_irc`'s client and its receiver implement them
+//! for Twitch, `MemoryChat` implements them in memory for tests and tools embedding the engine.
+
+use crate::config::normalize_channel;
+use crate::limits::{RoomStateUpdate, UserState};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use twitch_irc::{
+    login::LoginCredentials,
+    message::{
+        FollowersOnlyMode, JoinMessage, NoticeMessage, PartMessage, PrivmsgMessage,
+        RoomStateMessage, ServerMessage, UserStateMessage,
+    },
+    transport::Transport,
+    TwitchIRCClient,
+};
+
+/// `msg-id`s of the notices Twitch answers a failed `JOIN` with.
+const JOIN_FAILURE_NOTICES: &[&str] = &[
+    "msg_banned",
+    "msg_channel_blocked",
+    "msg_channel_suspended",
+    "msg_room_not_found",
+    "tos_ban",
+];
+
+pub type ChatError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A chat message, the same whether it came from Twitch, a recording or a test.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub timestamp: DateTime<Utc>,
+    /// Login of the channel, normalized.
+    pub channel: String,
+    /// Login of the sender, lowercase.
+    pub sender: String,
+    /// Tells senders apart even if they rename, the login if the source has no ids.
+    pub sender_id: String,
+    pub text: String,
+}
+
+impl From<PrivmsgMessage> for ChatMessage {
+    fn from(message: PrivmsgMessage) -> ChatMessage {
+        ChatMessage {
+            timestamp: message.server_timestamp,
+            channel: normalize_channel(&message.channel_login),
+            sender: message.sender.login.to_lowercase(),
+            sender_id: message.sender.id,
+            text: message.message_text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatEvent {
+    Message(ChatMessage),
+    /// The server confirmed joining a channel.
+    Joined(String),
+    /// The connection left a channel without being asked to.
+    Parted(String),
+    /// The server refused to join a channel, for the given reason.
+    JoinFailed(String, String),
+    /// The server is about to drop the connection, the sink reconnects by itself.
+    Reconnect,
+    /// The chat modes of a channel changed, or were announced on joining it.
+    RoomState(String, RoomStateUpdate),
+    /// The connection's own badges in a channel, announced on joining and after every message.
+    UserState(String, UserState),
+}
+
+impl ChatEvent {
+    /// The event a message from Twitch's IRC server stands for, if the engine cares about it.
+    pub fn from_twitch(message: ServerMessage) -> Option<ChatEvent> {
+        match message {
+            ServerMessage::Privmsg(message) => Some(ChatEvent::Message(message.into())),
+            ServerMessage::Join(JoinMessage { channel_login, .. }) => {
+                Some(ChatEvent::Joined(channel_login))
+            }
+            ServerMessage::Part(PartMessage { channel_login, .. }) => {
+                Some(ChatEvent::Parted(channel_login))
+            }
+            ServerMessage::Notice(NoticeMessage {
+                channel_login: Some(channel_login),
+                message_id: Some(message_id),
+                message_text,
+                ..
+            }) if JOIN_FAILURE_NOTICES.contains(&message_id.as_str()) => {
+                Some(ChatEvent::JoinFailed(channel_login, message_text))
+            }
+            ServerMessage::Reconnect(_) => Some(ChatEvent::Reconnect),
+            ServerMessage::RoomState(RoomStateMessage {
+                channel_login,
+                emote_only,
+                follwers_only,
+                slow_mode,
+                subscribers_only,
+                ..
+            }) => Some(ChatEvent::RoomState(
+                channel_login,
+                RoomStateUpdate {
+                    slow_mode,
+                    followers_only: follwers_only.map(|mode| match mode {
+                        FollowersOnlyMode::Disabled => None,
+                        FollowersOnlyMode::Enabled(duration) => Some(duration),
+                    }),
+                    subscribers_only,
+                    emote_only,
+                },
+            )),
+            ServerMessage::UserState(UserStateMessage {
+                channel_login,
+                badges,
+                ..
+            }) => {
+                let has_badge = |names: &[&str]| {
+                    badges
+                        .iter()
+                        .any(|badge| names.contains(&badge.name.as_str()))
+                };
+                Some(ChatEvent::UserState(
+                    channel_login,
+                    UserState {
+                        privileged: has_badge(&["broadcaster", "moderator", "vip"]),
+                        subscriber: has_badge(&["subscriber", "founder"]),
+                    },
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Where the engine sends its plays and `JOIN`s to.
+#[async_trait]
+pub trait ChatSink: Clone + Send + Sync + 'static {
+    async fn say(&self, channel: String, message: String) -> Result<(), ChatError>;
+
+    /// Asks to join `channel`, which is confirmed later on through `ChatEvent::Joined`.
+    fn join(&self, channel: String) -> Result<(), ChatError>;
+
+    fn part(&self, channel: String);
+
+    /// Whether the connection is currently in `channel`, as far as the sink knows.
+    async fn is_joined(&self, channel: String) -> bool;
+}
+
+/// Where the engine gets chat messages and changes of the connection from.
+#[async_trait]
+pub trait ChatSource: Send {
+    /// The next event, `None` once the connection is closed for good.
+    async fn next_event(&mut self) -> Option<ChatEvent>;
+}
+
+#[async_trait]
+impl<T: Transport, L: LoginCredentials> ChatSink for TwitchIRCClient<T, L> {
+    async fn say(&self, channel: String, message: String) -> Result<(), ChatError> {
+        TwitchIRCClient::say(self, channel, message)
+            .await
+            .map_err(|error| error.into())
+    }
+
+    fn join(&self, channel: String) -> Result<(), ChatError> {
+        TwitchIRCClient::join(self, channel).map_err(|error| error.into())
+    }
+
+    fn part(&self, channel: String) {
+        TwitchIRCClient::part(self, channel)
+    }
+
+    async fn is_joined(&self, channel: String) -> bool {
+        let (_, joined) = self.get_channel_status(channel).await;
+        joined
+    }
+}
+
+#[async_trait]
+impl ChatSource for mpsc::UnboundedReceiver<ServerMessage> {
+    async fn next_event(&mut self) -> Option<ChatEvent> {
+        loop {
+            if let Some(event) = ChatEvent::from_twitch(self.recv().await?) {
+                return Some(event);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ChatSource for mpsc::UnboundedReceiver<ChatEvent> {
+    async fn next_event(&mut self) -> Option<ChatEvent> {
+        self.recv().await
+    }
+}
+
+/// A chat that only exists in memory. Events pushed into it come out of the source returned
+/// along with it, joins are confirmed right away and whatever is said is kept to look at later.
+#[derive(Debug, Clone)]
+pub struct MemoryChat {
+    events: mpsc::UnboundedSender<ChatEvent>,
+    state: Arc<Mutex<MemoryChatState>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryChatState {
+    joined: HashSet<String>,
+    said: Vec<(String, String)>,
+}
+
+impl MemoryChat {
+    pub fn new() -> (MemoryChat, mpsc::UnboundedReceiver<ChatEvent>) {
+        let (events, source) = mpsc::unbounded_channel();
+        let chat = MemoryChat {
+            events,
+            state: Arc::new(Mutex::new(MemoryChatState::default())),
+        };
+        (chat, source)
+    }
+
+    /// Hands `event` to the source, dropped if the source is gone.
+    pub fn push(self: &MemoryChat, event: ChatEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Every message said so far along with its channel, oldest first.
+    pub fn said(self: &MemoryChat) -> Vec<(String, String)> {
+        self.state.lock().unwrap().said.clone()
+    }
+
+    pub fn joined(self: &MemoryChat) -> Vec<String> {
+        let mut joined: Vec<String> = self.state.lock().unwrap().joined.iter().cloned().collect();
+        joined.sort();
+        joined
+    }
+}
+
+#[async_trait]
+impl ChatSink for MemoryChat {
+    async fn say(&self, channel: String, message: String) -> Result<(), ChatError> {
+        self.state.lock().unwrap().said.push((channel, message));
+        Ok(())
+    }
+
+    fn join(&self, channel: String) -> Result<(), ChatError> {
+        self.state.lock().unwrap().joined.insert(channel.to_owned());
+        self.push(ChatEvent::Joined(channel));
+        Ok(())
+    }
+
+    fn part(&self, channel: String) {
+        self.state.lock().unwrap().joined.remove(&channel);
+    }
+
+    async fn is_joined(&self, channel: String) -> bool {
+        self.state.lock().unwrap().joined.contains(&channel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use twitch_irc::message::IRCMessage;
+
+    fn twitch(source: &str) -> Option<ChatEvent> {
+        ChatEvent::from_twitch(ServerMessage::try_from(IRCMessage::parse(source).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn normalizes_twitch_messages() {
+        let Some(ChatEvent::Message(message)) = twitch("@badge-info=;badges=;color=;display-name=Someone;emotes=;flags=;id=d7f03a35-f339-41ca-b4d4-7c0721438570;mod=0;room-id=11148817;subscriber=0;tmi-sent-ts=1594571566672;turbo=0;user-id=36175310;user-type= :someone!someone@someone.tmi.twitch.tv PRIVMSG #marbles :!play") else {
+            panic!("not a chat message");
+        };
+        assert_eq!(message.channel, "marbles");
+        assert_eq!(message.sender, "someone");
+        assert_eq!(message.sender_id, "36175310");
+        assert_eq!(message.text, "!play");
+        assert_eq!(message.timestamp.timestamp_millis(), 1594571566672);
+
+        assert_eq!(
+            twitch("@msg-id=msg_channel_suspended :tmi.twitch.tv NOTICE #marbles :This channel has been suspended."),
+            Some(ChatEvent::JoinFailed(
+                "marbles".to_owned(),
+                "This channel has been suspended.".to_owned()
+            ))
+        );
+        assert_eq!(
+            twitch("@msg-id=host_on :tmi.twitch.tv NOTICE #marbles :Now hosting someone."),
+            None
+        );
+        assert_eq!(twitch(":tmi.twitch.tv PING"), None);
+
+        assert_eq!(
+            twitch("@emote-only=0;followers-only=10;r9k=0;room-id=11148817;slow=30;subs-only=0 :tmi.twitch.tv ROOMSTATE #marbles"),
+            Some(ChatEvent::RoomState(
+                "marbles".to_owned(),
+                RoomStateUpdate {
+                    slow_mode: Some(Duration::from_secs(30)),
+                    followers_only: Some(Some(Duration::from_secs(600))),
+                    subscribers_only: Some(false),
+                    emote_only: Some(false),
+                }
+            ))
+        );
+        assert_eq!(
+            twitch("@badge-info=subscriber/8;badges=vip/1,subscriber/6;color=;display-name=Someone;emote-sets=0;mod=0;subscriber=1;user-type= :tmi.twitch.tv USERSTATE #marbles"),
+            Some(ChatEvent::UserState(
+                "marbles".to_owned(),
+                UserState {
+                    privileged: true,
+                    subscriber: true,
+                }
+            ))
+        );
+    }
+}
@@ -0,0 +1,49 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Where a channel gets the current time from: the system clock when live, or a virtual clock
+/// that only moves when told to, e.g. along the timestamps of a replayed chat log.
+#[derive(Debug, Clone, Default)]
+pub enum Clock {
+    #[default]
+    System,
+    Virtual(Arc<Mutex<Instant>>),
+}
+
+impl Clock {
+    /// A virtual clock standing still at the current instant.
+    pub fn new_virtual() -> Clock {
+        Clock::Virtual(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    pub fn now(self: &Clock) -> Instant {
+        match self {
+            Clock::System => Instant::now(),
+            Clock::Virtual(now) => *now.lock().unwrap(),
+        }
+    }
+
+    /// Moves a virtual clock forward, the system clock can't be moved.
+    pub fn advance(self: &Clock, duration: Duration) {
+        match self {
+            Clock::System => panic!("can't advance the system clock"),
+            Clock::Virtual(now) => *now.lock().unwrap() += duration,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_clock_only_moves_when_advanced() {
+        let clock = Clock::new_virtual();
+        let start = clock.now();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(clock.now(), start);
+
+        clock.clone().advance(Duration::from_secs(3));
+        assert_eq!(clock.now(), start + Duration::from_secs(3));
+    }
+}
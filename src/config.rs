@@ -0,0 +1,511 @@
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const DEFAULT_BUFFER_SIZE: usize = 10;
+pub const DEFAULT_TRESHHOLD: usize = 5;
+pub const DEFAULT_DELAY: u64 = 120;
+/// Longest `delay` in seconds, of a channel as well as of an account.
+pub const MAX_DELAY: u64 = 24 * 60 * 60;
+pub const DEFAULT_WAIT: u64 = 5;
+/// Longest `wait` and `wait_jitter` in seconds, also bounding an account's `send_offset`.
+pub const MAX_WAIT: u64 = 24 * 60 * 60;
+pub const DEFAULT_WINDOW: u64 = 30;
+/// Longest `window` in seconds, a day is far more than any race takes to fill.
+pub const MAX_WINDOW: u64 = 24 * 60 * 60;
+pub const DEFAULT_LOBBY_DURATION: u64 = 120;
+/// Longest `lobby_duration` in seconds, no lobby stays open for a day.
+pub const MAX_LOBBY_DURATION: u64 = 24 * 60 * 60;
+pub const DEFAULT_PLAY_MESSAGE: &str = "!play >:(";
+pub const DEFAULT_TRIGGER_PATTERN: &str = "^!play";
+pub const DEFAULT_ANNOUNCEMENT_PATTERN: &str = "(?i)!play";
+/// Seconds between the sends of two accounts that don't set their own `send_offset`.
+pub const DEFAULT_SEND_STAGGER: f64 = 0.5;
+
+/// Contents of the config file: a global section followed by optional
+/// `[channel.<name>]` tables overriding any of the global values and `[account.<login>]` tables
+/// of further accounts to play as.
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(flatten)]
+    pub global: ChannelConfig,
+
+    #[serde(default)]
+    pub channel: HashMap<String, ChannelConfig>,
+
+    #[serde(default)]
+    pub account: HashMap<String, AccountConfig>,
+}
+
+/// An `[account.<login>]` table: an account that sends plays through its own connection, while
+/// the trigger logic of every channel is shared by all accounts.
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    /// File containing the account's oauth token, must not be readable by everyone.
+    pub oauth_file: Option<PathBuf>,
+    /// JSON file with a refreshing token, like `--token-file`.
+    pub token_file: Option<PathBuf>,
+    /// Channels the account plays in, every channel if empty.
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Least seconds between two plays of the account in a channel, on top of the channel's
+    /// cooldown.
+    pub delay: Option<u64>,
+    /// Seconds after a play is due the account sends it, staggered by `DEFAULT_SEND_STAGGER` in
+    /// the order of the logins if unset.
+    pub send_offset: Option<f64>,
+}
+
+/// A set of optional channel parameters, as found in the config file or on the command line.
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelConfig {
+    pub buffer_size: Option<usize>,
+    pub treshhold: Option<usize>,
+    pub delay: Option<u64>,
+    pub wait: Option<u64>,
+    pub play_message: Option<String>,
+    pub announcers: Option<Vec<String>>,
+    pub announcement_patterns: Option<Vec<String>>,
+    pub trigger_policy: Option<TriggerPolicy>,
+    pub trigger_mode: Option<TriggerMode>,
+    pub window: Option<u64>,
+    pub ignore_own: Option<bool>,
+    pub ignored_users: Option<Vec<String>>,
+    pub triggers: Option<Vec<TriggerConfig>>,
+    pub dry_run: Option<bool>,
+    pub lobby_duration: Option<u64>,
+    pub lobby_min_matches: Option<usize>,
+    pub wait_percentile: Option<f64>,
+    pub wait_jitter: Option<f64>,
+}
+
+/// A `[[triggers]]` entry: a pattern chat messages are matched against case-insensitively and
+/// the message to join with once enough users sent a matching one, `play_message` if unset.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TriggerConfig {
+    pub pattern: String,
+    pub response: Option<String>,
+}
+
+/// How the crowd trigger decides that enough people want to play.
+#[derive(Deserialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerMode {
+    /// At least `treshhold` distinct users sent a matching message among the last `buffer_size`.
+    Buffer,
+    /// At least `treshhold` distinct users sent a matching message within the last `window` seconds.
+    Window,
+}
+
+/// How the crowd trigger (enough matching messages in the buffer) and the announcement trigger
+/// (a trusted announcer posting a message matching one of the announcement patterns) combine.
+#[derive(Deserialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerPolicy {
+    /// Join as soon as either of the triggers fires.
+    Either,
+    /// Join only once the crowd trigger fires after a recent announcement, or the other way around.
+    Both,
+}
+
+/// The fully resolved parameters a single channel runs with.
+#[derive(Debug, Clone)]
+pub struct ChannelParams {
+    pub buffer_size: usize,
+    pub treshhold: usize,
+    pub delay: Duration,
+    pub wait: Duration,
+    pub announcers: Vec<String>,
+    pub announcement_patterns: Vec<Regex>,
+    pub trigger_policy: TriggerPolicy,
+    pub trigger_mode: TriggerMode,
+    pub window: Duration,
+    pub ignore_own: bool,
+    pub ignored_users: Vec<String>,
+    pub triggers: Vec<Trigger>,
+    /// Log plays instead of sending them.
+    pub dry_run: bool,
+    /// How long a lobby stays open after the trigger fired.
+    pub lobby_duration: Duration,
+    /// Fewest trigger matches within the last `window` that keep a lobby open, 0 to only go by
+    /// `lobby_duration`.
+    pub lobby_min_matches: usize,
+    /// Send plays at this percentile of the learned join times instead of after `wait`.
+    pub wait_percentile: Option<f64>,
+    /// How far a learned wait is randomly moved either way.
+    pub wait_jitter: Duration,
+}
+
+/// A compiled trigger pattern together with the message answering it.
+#[derive(Debug, Clone)]
+pub struct Trigger {
+    pub pattern: Regex,
+    pub response: String,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Pattern(regex::Error),
+    Value(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(error) => write!(f, "could not read config file: {}", error),
+            ConfigError::Parse(error) => write!(f, "could not parse config file: {}", error),
+            ConfigError::Pattern(error) => write!(f, "invalid pattern: {}", error),
+            ConfigError::Value(error) => write!(f, "invalid value: {}", error),
+        }
+    }
+}
+
+/// Brings a channel name into the form Twitch uses for logins: lowercase, without leading `#`.
+pub fn normalize_channel(channel: &str) -> String {
+    channel.trim().trim_start_matches('#').to_lowercase()
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        let mut config: Config = toml::from_str(&contents).map_err(ConfigError::Parse)?;
+        config.channel = config
+            .channel
+            .into_iter()
+            .map(|(channel, channel_config)| (normalize_channel(&channel), channel_config))
+            .collect();
+        config.account = config
+            .account
+            .into_iter()
+            .map(|(login, mut account)| {
+                account.channels = account
+                    .channels
+                    .iter()
+                    .map(|channel| normalize_channel(channel))
+                    .collect();
+                (login.to_lowercase(), account)
+            })
+            .collect();
+        for (login, account) in config.account.iter() {
+            if account.oauth_file.is_some() == account.token_file.is_some() {
+                return Err(ConfigError::Value(format!(
+                    "account {} needs either an oauth_file or a token_file",
+                    login
+                )));
+            }
+            if let Some(send_offset) = account.send_offset {
+                if !(send_offset.is_finite() && send_offset >= 0.0) {
+                    return Err(ConfigError::Value(format!(
+                        "send_offset {} of account {} is not a positive number of seconds",
+                        send_offset, login
+                    )));
+                }
+                if send_offset > MAX_WAIT as f64 {
+                    return Err(ConfigError::Value(format!(
+                        "send_offset {} of account {} is longer than {} seconds",
+                        send_offset, login, MAX_WAIT
+                    )));
+                }
+            }
+            if let Some(delay) = account.delay {
+                at_most(&format!("delay of account {}", login), delay, MAX_DELAY)?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Resolves the parameters for `channel`. Values are taken from, in increasing order of
+    /// precedence: the built-in defaults, the global section, the channel's own table and
+    /// finally the command line flags in `overrides`.
+    pub fn resolve(
+        &self,
+        channel: &str,
+        overrides: &ChannelConfig,
+    ) -> Result<ChannelParams, ConfigError> {
+        let mut merged = self.global.clone();
+        if let Some(channel_config) = self.channel.get(channel) {
+            merged = merged.merge(channel_config);
+        }
+        merged.merge(overrides).into_params()
+    }
+}
+
+impl ChannelConfig {
+    /// Returns a copy of `self` with every value set in `other` replaced.
+    fn merge(self, other: &ChannelConfig) -> ChannelConfig {
+        ChannelConfig {
+            buffer_size: other.buffer_size.or(self.buffer_size),
+            treshhold: other.treshhold.or(self.treshhold),
+            delay: other.delay.or(self.delay),
+            wait: other.wait.or(self.wait),
+            play_message: other.play_message.clone().or(self.play_message),
+            announcers: other.announcers.clone().or(self.announcers),
+            announcement_patterns: other
+                .announcement_patterns
+                .clone()
+                .or(self.announcement_patterns),
+            trigger_policy: other.trigger_policy.or(self.trigger_policy),
+            trigger_mode: other.trigger_mode.or(self.trigger_mode),
+            window: other.window.or(self.window),
+            ignore_own: other.ignore_own.or(self.ignore_own),
+            ignored_users: other.ignored_users.clone().or(self.ignored_users),
+            triggers: other.triggers.clone().or(self.triggers),
+            dry_run: other.dry_run.or(self.dry_run),
+            lobby_duration: other.lobby_duration.or(self.lobby_duration),
+            lobby_min_matches: other.lobby_min_matches.or(self.lobby_min_matches),
+            wait_percentile: other.wait_percentile.or(self.wait_percentile),
+            wait_jitter: other.wait_jitter.or(self.wait_jitter),
+        }
+    }
+
+    fn into_params(self) -> Result<ChannelParams, ConfigError> {
+        let announcement_patterns = self
+            .announcement_patterns
+            .unwrap_or_else(|| vec![DEFAULT_ANNOUNCEMENT_PATTERN.to_owned()])
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<Vec<Regex>, regex::Error>>()
+            .map_err(ConfigError::Pattern)?;
+        let play_message = self
+            .play_message
+            .unwrap_or_else(|| DEFAULT_PLAY_MESSAGE.to_owned());
+        let triggers = self
+            .triggers
+            .unwrap_or_else(|| {
+                vec![TriggerConfig {
+                    pattern: DEFAULT_TRIGGER_PATTERN.to_owned(),
+                    response: None,
+                }]
+            })
+            .into_iter()
+            .map(|trigger| {
+                Ok(Trigger {
+                    pattern: RegexBuilder::new(&trigger.pattern)
+                        .case_insensitive(true)
+                        .build()
+                        .map_err(ConfigError::Pattern)?,
+                    response: trigger.response.unwrap_or_else(|| play_message.to_owned()),
+                })
+            })
+            .collect::<Result<Vec<Trigger>, ConfigError>>()?;
+        if triggers.is_empty() {
+            return Err(ConfigError::Value(
+                "triggers needs at least one pattern".to_owned(),
+            ));
+        }
+        let buffer_size = self.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+        if buffer_size == 0 {
+            return Err(ConfigError::Value(
+                "buffer_size must be at least 1".to_owned(),
+            ));
+        }
+        let delay = at_most("delay", self.delay.unwrap_or(DEFAULT_DELAY), MAX_DELAY)?;
+        let wait = at_most("wait", self.wait.unwrap_or(DEFAULT_WAIT), MAX_WAIT)?;
+        let window = at_most("window", self.window.unwrap_or(DEFAULT_WINDOW), MAX_WINDOW)?;
+        let lobby_duration = at_most(
+            "lobby_duration",
+            self.lobby_duration.unwrap_or(DEFAULT_LOBBY_DURATION),
+            MAX_LOBBY_DURATION,
+        )?;
+        if let Some(wait_percentile) = self.wait_percentile {
+            if !(0.0..=100.0).contains(&wait_percentile) {
+                return Err(ConfigError::Value(format!(
+                    "wait_percentile {} is not between 0 and 100",
+                    wait_percentile
+                )));
+            }
+        }
+        let wait_jitter = self.wait_jitter.unwrap_or(0.0);
+        let wait_jitter = Duration::try_from_secs_f64(wait_jitter).map_err(|error| {
+            ConfigError::Value(format!("wait_jitter {:?}: {}", wait_jitter, error))
+        })?;
+        if wait_jitter > Duration::from_secs(MAX_WAIT) {
+            return Err(ConfigError::Value(format!(
+                "wait_jitter {:?} is longer than {} seconds",
+                wait_jitter, MAX_WAIT
+            )));
+        }
+
+        Ok(ChannelParams {
+            buffer_size,
+            treshhold: self.treshhold.unwrap_or(DEFAULT_TRESHHOLD),
+            delay: Duration::from_secs(delay),
+            wait: Duration::from_secs(wait),
+            announcers: self
+                .announcers
+                .unwrap_or_default()
+                .iter()
+                .map(|announcer| announcer.to_lowercase())
+                .collect(),
+            announcement_patterns,
+            trigger_policy: self.trigger_policy.unwrap_or(TriggerPolicy::Either),
+            trigger_mode: self.trigger_mode.unwrap_or(TriggerMode::Buffer),
+            window: Duration::from_secs(window),
+            ignore_own: self.ignore_own.unwrap_or(false),
+            ignored_users: self
+                .ignored_users
+                .unwrap_or_default()
+                .iter()
+                .map(|user| user.to_lowercase())
+                .collect(),
+            triggers,
+            dry_run: self.dry_run.unwrap_or(false),
+            lobby_duration: Duration::from_secs(lobby_duration),
+            lobby_min_matches: self.lobby_min_matches.unwrap_or(0),
+            wait_percentile: self.wait_percentile,
+            wait_jitter,
+        })
+    }
+}
+
+/// Rejects a `setting` of more than `max` seconds, which would overflow the instants it's added to.
+fn at_most(setting: &str, seconds: u64, max: u64) -> Result<u64, ConfigError> {
+    if seconds > max {
+        return Err(ConfigError::Value(format!(
+            "{} {} is longer than {} seconds",
+            setting, seconds, max
+        )));
+    }
+    Ok(seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("marblejoiner-{}-{}.toml", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn resolves_defaults_then_global_then_channel_then_overrides() {
+        let path = write_config(
+            "precedence",
+            r##"
+                treshhold = 3
+                delay = 60
+                wait = 2
+
+                [channel."#Marbles"]
+                delay = 90
+                wait = 4
+            "##,
+        );
+        let config = Config::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let overrides = ChannelConfig {
+            wait: Some(1),
+            ..ChannelConfig::default()
+        };
+
+        let marbles = config.resolve("marbles", &overrides).unwrap();
+        assert_eq!(marbles.buffer_size, DEFAULT_BUFFER_SIZE);
+        assert_eq!(marbles.treshhold, 3);
+        assert_eq!(marbles.delay, Duration::from_secs(90));
+        assert_eq!(marbles.wait, Duration::from_secs(1));
+
+        let elsewhere = config
+            .resolve("pixelbypixel", &ChannelConfig::default())
+            .unwrap();
+        assert_eq!(elsewhere.delay, Duration::from_secs(60));
+        assert_eq!(elsewhere.wait, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn rejects_an_empty_buffer() {
+        let overrides = ChannelConfig {
+            buffer_size: Some(0),
+            ..ChannelConfig::default()
+        };
+        assert!(matches!(
+            Config::default().resolve("marbles", &overrides),
+            Err(ConfigError::Value(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_windows_longer_than_a_day() {
+        let overrides = ChannelConfig {
+            window: Some(99_999_999_999_999),
+            ..ChannelConfig::default()
+        };
+        assert!(matches!(
+            Config::default().resolve("marbles", &overrides),
+            Err(ConfigError::Value(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_lobby_durations_longer_than_a_day() {
+        let overrides = ChannelConfig {
+            lobby_duration: Some(u64::MAX),
+            ..ChannelConfig::default()
+        };
+        assert!(matches!(
+            Config::default().resolve("marbles", &overrides),
+            Err(ConfigError::Value(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_delays_and_waits_longer_than_a_day() {
+        for overrides in [
+            ChannelConfig {
+                delay: Some(u64::MAX),
+                ..ChannelConfig::default()
+            },
+            ChannelConfig {
+                wait: Some(u64::MAX),
+                ..ChannelConfig::default()
+            },
+            ChannelConfig {
+                wait_jitter: Some(1e10),
+                ..ChannelConfig::default()
+            },
+        ] {
+            assert!(matches!(
+                Config::default().resolve("marbles", &overrides),
+                Err(ConfigError::Value(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_an_empty_trigger_list() {
+        let overrides = ChannelConfig {
+            triggers: Some(Vec::new()),
+            ..ChannelConfig::default()
+        };
+        assert!(matches!(
+            Config::default().resolve("marbles", &overrides),
+            Err(ConfigError::Value(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_a_wait_jitter_that_is_no_duration() {
+        for wait_jitter in [-1.0, f64::NAN, 1e300] {
+            let overrides = ChannelConfig {
+                wait_jitter: Some(wait_jitter),
+                ..ChannelConfig::default()
+            };
+            assert!(matches!(
+                Config::default().resolve("marbles", &overrides),
+                Err(ConfigError::Value(_))
+            ));
+        }
+    }
+}
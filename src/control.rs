@@ -0,0 +1,224 @@
+//! Commands steering the running app, read from the interactive console on stdin or the HTTP
+//! API, and what the message loop answers them with.
+
+use crate::config::normalize_channel;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::{mpsc, oneshot};
+
+const USAGE: &str = "commands: join <channel>, part <channel>, pause [channel], resume [channel], play <channel>, status, set <channel> <setting> <value>";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Join(String),
+    Part(String),
+    /// Pauses one channel, or all of them if none is given.
+    Pause(Option<String>),
+    Resume(Option<String>),
+    Status,
+    /// Plays in the channel right away, regardless of threshold and cooldown.
+    Play(String),
+    Set {
+        channel: String,
+        setting: String,
+        value: String,
+    },
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Command, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let channel = |word: &str| {
+            let channel = normalize_channel(word);
+            if channel.is_empty() {
+                Err(USAGE.to_owned())
+            } else {
+                Ok(channel)
+            }
+        };
+        match words.as_slice() {
+            ["join", name] => Ok(Command::Join(channel(name)?)),
+            ["part", name] => Ok(Command::Part(channel(name)?)),
+            ["pause"] => Ok(Command::Pause(None)),
+            ["pause", name] => Ok(Command::Pause(Some(channel(name)?))),
+            ["resume"] => Ok(Command::Resume(None)),
+            ["resume", name] => Ok(Command::Resume(Some(channel(name)?))),
+            ["status"] => Ok(Command::Status),
+            ["play", name] => Ok(Command::Play(channel(name)?)),
+            ["set", name, setting, value] => Ok(Command::Set {
+                channel: channel(name)?,
+                setting: setting.to_lowercase(),
+                value: value.to_string(),
+            }),
+            _ => Err(USAGE.to_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Done(String),
+    NotFound(String),
+    Invalid(String),
+    Status(Status),
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reply::Done(message) | Reply::NotFound(message) | Reply::Invalid(message) => {
+                write!(f, "{}", message)
+            }
+            Reply::Status(status) if status.channels.is_empty() => write!(f, "no channels"),
+            Reply::Status(status) => {
+                let lines: Vec<String> = status.channels.iter().map(ToString::to_string).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub healthy: bool,
+    pub channels: Vec<ChannelStatus>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChannelStatus {
+    pub channel: String,
+    pub health: String,
+    pub paused: bool,
+    pub buffered_plays: usize,
+    pub buffer_size: usize,
+    pub treshhold: usize,
+    /// Seconds until the cooldown after the last play is over.
+    pub next_play_in: u64,
+    pub last_trigger: Option<TriggerRecord>,
+    pub last_send: Option<SendRecord>,
+    /// Whether the last race lobby is estimated to still take players.
+    pub lobby_open: bool,
+}
+
+impl fmt::Display for ChannelStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}, {}, {}/{} plays buffered, treshhold {}, ",
+            self.channel,
+            self.health,
+            if self.paused { "paused" } else { "active" },
+            self.buffered_plays,
+            self.buffer_size,
+            self.treshhold
+        )?;
+        if self.next_play_in == 0 {
+            write!(f, "ready to play")?;
+        } else {
+            write!(f, "cooldown {}s", self.next_play_in)?;
+        }
+        if self.lobby_open {
+            write!(f, ", lobby open")?;
+        }
+        if let Some(last_send) = &self.last_send {
+            match &last_send.error {
+                None => write!(f, ", last play sent at {}", last_send.at)?,
+                Some(error) => write!(f, ", last play failed at {}: {}", last_send.at, error)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// When the channel last triggered a play, and through which trigger pattern. `pattern` is
+/// `None` for plays forced through a command.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TriggerRecord {
+    pub at: DateTime<Utc>,
+    pub pattern: Option<String>,
+    pub announced: bool,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SendRecord {
+    pub at: DateTime<Utc>,
+    pub response: String,
+    pub error: Option<String>,
+}
+
+/// A command on its way into the message loop, along with where to send the outcome.
+#[derive(Debug)]
+pub struct ControlRequest {
+    pub command: Command,
+    pub reply: oneshot::Sender<Reply>,
+}
+
+/// Hands `command` to the message loop and waits for its reply, `None` if the loop is gone.
+pub async fn request(
+    requests: &mpsc::UnboundedSender<ControlRequest>,
+    command: Command,
+) -> Option<Reply> {
+    let (reply, outcome) = oneshot::channel();
+    requests.send(ControlRequest { command, reply }).ok()?;
+    outcome.await.ok()
+}
+
+/// Reads commands from stdin line by line and prints the outcome of each, until stdin closes or
+/// the message loop is gone.
+pub async fn run_console(requests: mpsc::UnboundedSender<ControlRequest>) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let command = match line.parse() {
+            Ok(command) => command,
+            Err(usage) => {
+                println!("{}", usage);
+                continue;
+            }
+        };
+        match request(&requests, command).await {
+            Some(reply) => println!("{}", reply),
+            None => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!("join #Marbles".parse(), Ok(Command::Join("marbles".into())));
+        assert_eq!("part marbles".parse(), Ok(Command::Part("marbles".into())));
+        assert_eq!("pause".parse(), Ok(Command::Pause(None)));
+        assert_eq!("play marbles".parse(), Ok(Command::Play("marbles".into())));
+        assert_eq!(
+            " resume  marbles ".parse(),
+            Ok(Command::Resume(Some("marbles".into())))
+        );
+        assert_eq!(
+            "set marbles Treshhold 7".parse(),
+            Ok(Command::Set {
+                channel: "marbles".into(),
+                setting: "treshhold".into(),
+                value: "7".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        assert!("join".parse::<Command>().is_err());
+        assert!("join #".parse::<Command>().is_err());
+        assert!("set marbles treshhold".parse::<Command>().is_err());
+        assert!("restart".parse::<Command>().is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use crate::token_storage::JsonFileTokenStorage;
+use async_trait::async_trait;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use twitch_irc::login::{
+    CredentialsPair, LoginCredentials, RefreshingLoginCredentials, RefreshingLoginError,
+    StaticLoginCredentials,
+};
+
+pub const OAUTH_ENV_VAR: &str = "MARBLEJOINER_OAUTH";
+
+#[derive(Debug)]
+pub enum CredentialsError {
+    Missing,
+    Empty,
+    Read(PathBuf, io::Error),
+    WorldReadable(PathBuf),
+    Stdin(io::Error),
+}
+
+impl fmt::Display for CredentialsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CredentialsError::Missing => write!(
+                f,
+                "no oauth token given, use --oauth-file, --oauth-stdin or the {} environment variable",
+                OAUTH_ENV_VAR
+            ),
+            CredentialsError::Empty => write!(f, "the oauth token is empty"),
+            CredentialsError::Read(path, error) => {
+                write!(f, "could not read {}: {}", path.display(), error)
+            }
+            CredentialsError::WorldReadable(path) => write!(
+                f,
+                "{} is readable by everyone, restrict it with chmod o-r first",
+                path.display()
+            ),
+            CredentialsError::Stdin(error) => write!(f, "could not read from stdin: {}", error),
+        }
+    }
+}
+
+/// Finds the oauth token in, by order of precedence: the deprecated positional argument, the
+/// token file, the `MARBLEJOINER_OAUTH` environment variable and finally stdin.
+pub fn read_oauth(
+    positional: Option<String>,
+    file: Option<&Path>,
+    stdin: bool,
+) -> Result<String, CredentialsError> {
+    let token = if let Some(token) = positional {
+        warn!(
+            "passing the oauth token as an argument leaks it into your shell history and process list, use --oauth-file, --oauth-stdin or {} instead",
+            OAUTH_ENV_VAR
+        );
+        token
+    } else if let Some(path) = file {
+        read_oauth_file(path)?
+    } else if let Some(token) = env::var(OAUTH_ENV_VAR)
+        .ok()
+        .filter(|token| !token.is_empty())
+    {
+        token
+    } else if stdin {
+        read_oauth_stdin()?
+    } else {
+        return Err(CredentialsError::Missing);
+    };
+
+    let token = token.trim().to_owned();
+    if token.is_empty() {
+        return Err(CredentialsError::Empty);
+    }
+    Ok(token)
+}
+
+fn read_oauth_file(path: &Path) -> Result<String, CredentialsError> {
+    let metadata =
+        fs::metadata(path).map_err(|error| CredentialsError::Read(path.into(), error))?;
+    if is_world_readable(&metadata) {
+        return Err(CredentialsError::WorldReadable(path.into()));
+    }
+    fs::read_to_string(path).map_err(|error| CredentialsError::Read(path.into(), error))
+}
+
+#[cfg(unix)]
+pub fn is_world_readable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o004 != 0
+}
+
+#[cfg(not(unix))]
+pub fn is_world_readable(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+fn read_oauth_stdin() -> Result<String, CredentialsError> {
+    let stdin = io::stdin();
+    if stdin.is_terminal() {
+        eprint!("oauth token: ");
+        io::stderr().flush().map_err(CredentialsError::Stdin)?;
+    }
+    let mut token = String::new();
+    stdin
+        .lock()
+        .read_line(&mut token)
+        .map_err(CredentialsError::Stdin)?;
+    Ok(token)
+}
+
+/// Either a fixed oauth token or one twitch-irc keeps refreshing through the token file.
+#[derive(Debug)]
+pub enum Credentials {
+    Static(StaticLoginCredentials),
+    Refreshing(RefreshingLoginCredentials<JsonFileTokenStorage>),
+}
+
+impl Credentials {
+    pub fn refreshing(login: String, token_storage: JsonFileTokenStorage) -> Credentials {
+        Credentials::Refreshing(RefreshingLoginCredentials::init_with_username(
+            Some(login),
+            token_storage.client_id().to_owned(),
+            token_storage.client_secret().to_owned(),
+            token_storage,
+        ))
+    }
+}
+
+#[async_trait]
+impl LoginCredentials for Credentials {
+    type Error = RefreshingLoginError<JsonFileTokenStorage>;
+
+    async fn get_credentials(&self) -> Result<CredentialsPair, Self::Error> {
+        match self {
+            Credentials::Static(credentials) => Ok(credentials.credentials.clone()),
+            Credentials::Refreshing(credentials) => credentials.get_credentials().await,
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn token_file(name: &str, mode: u32) -> PathBuf {
+        let path = env::temp_dir().join(format!("marblejoiner-{}-{}", std::process::id(), name));
+        fs::write(&path, "oauth:abc123\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_private_token_file() {
+        let path = token_file("private", 0o600);
+        let token = read_oauth(None, Some(&path), false);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(token.unwrap(), "oauth:abc123");
+    }
+
+    #[test]
+    fn refuses_world_readable_token_file() {
+        let path = token_file("public", 0o644);
+        let token = read_oauth(None, Some(&path), false);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(token, Err(CredentialsError::WorldReadable(_))));
+    }
+
+    #[test]
+    fn prefers_positional_token() {
+        let token = read_oauth(Some("abc123".to_owned()), None, false);
+
+        assert_eq!(token.unwrap(), "abc123");
+    }
+}
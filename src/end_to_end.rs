@@ -0,0 +1,146 @@
+//! The whole engine talking to a fake Twitch IRC server over TCP, from logging in and joining to
+//! the plays it sends. Cooldowns run on a virtual clock, waits take real time.
+
+use crate::clock::Clock;
+use crate::config::ChannelConfig;
+use crate::fake_irc::{FakeClient, FakeConnection, FakeServer};
+use crate::{ChannelFactory, Engine};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use twitch_irc::{login::StaticLoginCredentials, ClientConfig};
+
+/// A play as it goes over the wire, `say` escapes it with a `. ` that Twitch strips again.
+const PLAY: &str = "PRIVMSG #marbles :. !play >:(";
+/// How long to watch for a play that must not be sent.
+const QUIET: Duration = Duration::from_millis(500);
+
+/// Runs the engine in `marbles` and returns the server along with its side of the connection
+/// once the client logged in and joined.
+async fn start(overrides: ChannelConfig) -> (FakeServer, FakeConnection, Clock) {
+    let mut server = FakeServer::start().await;
+    server.use_for_this_thread();
+    let channel_factory = ChannelFactory::for_test(overrides);
+    let clock = channel_factory.clock.clone();
+    let (incoming_messages, client) =
+        FakeClient::new(ClientConfig::new_simple(StaticLoginCredentials::anonymous()));
+    let mut engine = Engine::new(channel_factory, client);
+    engine.add_channel("marbles".to_owned()).unwrap();
+    let (_, requests) = mpsc::unbounded_channel();
+    tokio::spawn(engine.run(incoming_messages, requests));
+
+    let mut connection = server.accept().await;
+    connection.handshake().await;
+    connection.join("marbles").await;
+    (server, connection, clock)
+}
+
+async fn burst(connection: &mut FakeConnection, senders: &[&str]) {
+    for sender in senders {
+        connection.privmsg("marbles", sender, "!play").await;
+    }
+}
+
+#[tokio::test]
+async fn plays_once_after_the_wait_when_the_treshhold_is_reached() {
+    let (_server, mut connection, _) = start(ChannelConfig {
+        wait: Some(1),
+        ..ChannelConfig::default()
+    })
+    .await;
+    connection.ping().await;
+
+    burst(&mut connection, &["a", "b", "c", "d"]).await;
+    connection.privmsg("marbles", "e", "hello").await;
+    connection.expect_nothing("PRIVMSG", QUIET).await;
+
+    burst(&mut connection, &["e"]).await;
+    let triggered_at = Instant::now();
+    connection.expect(PLAY).await;
+    let waited = triggered_at.elapsed();
+    assert!(
+        waited >= Duration::from_secs(1),
+        "played after {:?}",
+        waited
+    );
+    assert!(waited < Duration::from_secs(2), "played after {:?}", waited);
+
+    burst(&mut connection, &["f", "g"]).await;
+    connection.expect_nothing("PRIVMSG", QUIET).await;
+}
+
+#[tokio::test]
+async fn one_chatter_spamming_does_not_trigger() {
+    let (_server, mut connection, _) = start(ChannelConfig {
+        wait: Some(0),
+        ..ChannelConfig::default()
+    })
+    .await;
+
+    burst(&mut connection, &["spammer"; 10]).await;
+    burst(&mut connection, &["a", "b", "c"]).await;
+    connection.expect_nothing("PRIVMSG", QUIET).await;
+
+    burst(&mut connection, &["d"]).await;
+    connection.expect(PLAY).await;
+}
+
+#[tokio::test]
+async fn cooldown_holds_back_plays_until_the_delay_is_over() {
+    let (_server, mut connection, clock) = start(ChannelConfig {
+        wait: Some(0),
+        delay: Some(120),
+        ..ChannelConfig::default()
+    })
+    .await;
+
+    burst(&mut connection, &["a", "b", "c", "d", "e"]).await;
+    connection.expect(PLAY).await;
+
+    clock.advance(Duration::from_secs(119));
+    burst(&mut connection, &["f", "g", "h", "i", "j"]).await;
+    connection.expect_nothing("PRIVMSG", QUIET).await;
+
+    clock.advance(Duration::from_secs(1));
+    burst(&mut connection, &["k"]).await;
+    connection.expect(PLAY).await;
+}
+
+#[tokio::test]
+async fn keeps_playing_after_a_reconnect() {
+    let (mut server, mut connection, _) = start(ChannelConfig {
+        wait: Some(0),
+        ..ChannelConfig::default()
+    })
+    .await;
+
+    burst(&mut connection, &["a", "b", "c"]).await;
+    connection.send(":tmi.twitch.tv RECONNECT").await;
+    let mut connection = server.accept().await;
+    connection.handshake().await;
+    connection.join("marbles").await;
+
+    burst(&mut connection, &["d", "e"]).await;
+    connection.expect(PLAY).await;
+}
+
+#[tokio::test]
+async fn withholds_plays_while_the_chat_mode_would_reject_them() {
+    let (_server, mut connection, _) = start(ChannelConfig {
+        wait: Some(0),
+        ..ChannelConfig::default()
+    })
+    .await;
+    connection
+        .send("@emote-only=1;followers-only=-1;r9k=0;room-id=11148817;slow=0;subs-only=0 :tmi.twitch.tv ROOMSTATE #marbles")
+        .await;
+
+    burst(&mut connection, &["a", "b", "c", "d", "e"]).await;
+    connection.expect_nothing("PRIVMSG", QUIET).await;
+
+    connection
+        .send("@emote-only=0;room-id=11148817 :tmi.twitch.tv ROOMSTATE #marbles")
+        .await;
+    // the withheld play didn't start the cooldown
+    burst(&mut connection, &["f", "g", "h", "i", "j"]).await;
+    connection.expect(PLAY).await;
+}
@@ -0,0 +1,155 @@
+//! A local stand-in for Twitch's IRC server and the endpoint connecting the transports of
+//! `transport` to it, for tests.
+
+use crate::transport::{Endpoint, Plain, Server};
+use std::cell::Cell;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpListener;
+use twitch_irc::login::StaticLoginCredentials;
+use twitch_irc::transport::tcp::TCPTransport;
+use twitch_irc::TwitchIRCClient;
+
+thread_local! {
+    /// Where `FakeEndpoint` points to. twitch-irc gives a client no way to pass its own server to
+    /// the transport, so this is as close as it gets: thread local, so tests running in parallel
+    /// each talk to their own server, which works because `#[tokio::test]` runs every task on the
+    /// test's thread.
+    static SERVER_ADDRESS: Cell<Option<SocketAddr>> = const { Cell::new(None) };
+}
+
+/// An anonymous client connecting to the `FakeServer` of the current thread.
+pub type FakeClient = TwitchIRCClient<TCPTransport<Plain<FakeEndpoint>>, StaticLoginCredentials>;
+
+const EXPECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct FakeServer {
+    listener: TcpListener,
+}
+
+impl FakeServer {
+    pub async fn start() -> FakeServer {
+        FakeServer {
+            listener: TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        }
+    }
+
+    /// Makes every connection to `FakeEndpoint` made on the current thread go to this server.
+    pub fn use_for_this_thread(&self) {
+        let address = self.listener.local_addr().unwrap();
+        SERVER_ADDRESS.with(|server_address| server_address.set(Some(address)));
+    }
+
+    pub async fn accept(&mut self) -> FakeConnection {
+        let (socket, _) = tokio::time::timeout(EXPECT_TIMEOUT, self.listener.accept())
+            .await
+            .expect("client did not connect")
+            .unwrap();
+        let (read_half, write_half) = socket.into_split();
+        FakeConnection {
+            lines: BufReader::new(read_half).lines(),
+            write_half,
+        }
+    }
+}
+
+pub struct FakeConnection {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    write_half: OwnedWriteHalf,
+}
+
+impl FakeConnection {
+    /// Reads lines from the client until one starts with `prefix` and returns it.
+    pub async fn expect(&mut self, prefix: &str) -> String {
+        let read = async {
+            loop {
+                match self.lines.next_line().await.unwrap() {
+                    Some(line) if line.starts_with(prefix) => return line,
+                    Some(_) => continue,
+                    None => panic!("client disconnected while waiting for {}", prefix),
+                }
+            }
+        };
+        tokio::time::timeout(EXPECT_TIMEOUT, read)
+            .await
+            .unwrap_or_else(|_| panic!("client did not send {}", prefix))
+    }
+
+    pub async fn send(&mut self, line: &str) {
+        self.write_half
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .await
+            .unwrap();
+    }
+
+    pub async fn confirm_join(&mut self, channel: &str) {
+        self.send(&format!(
+            ":justinfan12345!justinfan12345@justinfan12345.tmi.twitch.tv JOIN #{}",
+            channel
+        ))
+        .await;
+    }
+
+    /// Goes through the login like Twitch does for an anonymous client, which sends no `PASS`.
+    pub async fn handshake(&mut self) {
+        let capabilities = self.expect("CAP REQ").await;
+        assert!(capabilities.contains("twitch.tv/tags"));
+        self.expect("NICK justinfan12345").await;
+        self.send(":tmi.twitch.tv CAP * ACK :twitch.tv/tags twitch.tv/commands")
+            .await;
+        self.send(":tmi.twitch.tv 001 justinfan12345 :Welcome, GLHF!")
+            .await;
+    }
+
+    /// Accepts the `JOIN` of `channel` once the client asks for it.
+    pub async fn join(&mut self, channel: &str) {
+        self.expect(&format!("JOIN #{}", channel)).await;
+        self.confirm_join(channel).await;
+    }
+
+    /// Pings the client like Twitch does every few minutes and waits for its answer.
+    pub async fn ping(&mut self) {
+        self.send("PING :tmi.twitch.tv").await;
+        self.expect("PONG").await;
+    }
+
+    /// Sends a chat message with the tags Twitch adds, from a user whose id is their login.
+    pub async fn privmsg(&mut self, channel: &str, sender: &str, text: &str) {
+        self.send(&format!(
+            "@badge-info=;badges=;color=;display-name={sender};emotes=;flags=;id=d7f03a35-f339-41ca-b4d4-7c0721438570;mod=0;room-id=11148817;subscriber=0;tmi-sent-ts={timestamp};turbo=0;user-id={sender};user-type= :{sender}!{sender}@{sender}.tmi.twitch.tv PRIVMSG #{channel} :{text}",
+            timestamp = chrono::Utc::now().timestamp_millis(),
+        ))
+        .await;
+    }
+
+    /// Fails if the client sends a line starting with `prefix` within `duration`.
+    pub async fn expect_nothing(&mut self, prefix: &str, duration: Duration) {
+        let read = async {
+            loop {
+                match self.lines.next_line().await.unwrap() {
+                    Some(line) if line.starts_with(prefix) => panic!("client sent {}", line),
+                    Some(_) => continue,
+                    None => return,
+                }
+            }
+        };
+        let _ = tokio::time::timeout(duration, read).await;
+    }
+}
+
+/// The `FakeServer` registered for the current thread.
+pub struct FakeEndpoint;
+
+impl Endpoint for FakeEndpoint {
+    fn server() -> Server {
+        let address = SERVER_ADDRESS
+            .with(|server_address| server_address.get())
+            .expect("no fake server registered for this thread");
+        Server {
+            host: address.ip().to_string(),
+            port: address.port(),
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! A small JSON API to watch and steer the app over HTTP. It has no authentication, so it should
+//! only ever listen on localhost or a trusted network.
+//!
+//! * `GET /status` reports every channel
+//! * `GET /metrics` exports counters in the Prometheus text format
+//! * `POST /channels/<channel>` joins a channel, `DELETE /channels/<channel>` parts it
+//! * `POST /channels/<channel>/pause`, `/resume` and `/play` pause, resume or force a play
+//! * `POST /pause` and `POST /resume` pause or resume all channels
+
+use crate::config::normalize_channel;
+use crate::control::{self, Command, ControlRequest, Reply};
+use crate::metrics::Metrics;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::json;
+use std::convert::Infallible;
+use std::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// Answers requests on `listener` until the message loop is gone.
+pub async fn serve(
+    listener: TcpListener,
+    requests: mpsc::UnboundedSender<ControlRequest>,
+    metrics: Metrics,
+) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let requests = requests.clone();
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let requests = requests.clone();
+                let metrics = metrics.clone();
+                async move { Ok::<_, Infallible>(respond(request, &requests, &metrics).await) }
+            }))
+        }
+    });
+    Server::from_tcp(listener)?.serve(make_service).await
+}
+
+async fn respond(
+    request: Request<Body>,
+    requests: &mpsc::UnboundedSender<ControlRequest>,
+    metrics: &Metrics,
+) -> Response<Body> {
+    // the registry is shared, no need to bother the message loop
+    if request.method() == Method::GET && request.uri().path() == "/metrics" {
+        return Response::builder()
+            .header("content-type", prometheus::TEXT_FORMAT)
+            .body(Body::from(metrics.encode()))
+            .unwrap();
+    }
+    let Some(command) = route(request.method(), request.uri().path()) else {
+        return json_response(StatusCode::NOT_FOUND, json!({ "error": "not found" }));
+    };
+    match control::request(requests, command).await {
+        Some(Reply::Done(message)) => json_response(StatusCode::OK, json!({ "message": message })),
+        Some(Reply::NotFound(error)) => {
+            json_response(StatusCode::NOT_FOUND, json!({ "error": error }))
+        }
+        Some(Reply::Invalid(error)) => {
+            json_response(StatusCode::BAD_REQUEST, json!({ "error": error }))
+        }
+        Some(Reply::Status(status)) => json_response(StatusCode::OK, json!(status)),
+        None => json_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({ "error": "shutting down" }),
+        ),
+    }
+}
+
+fn route(method: &Method, path: &str) -> Option<Command> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let channel = |name: &str| Some(normalize_channel(name)).filter(|name| !name.is_empty());
+    match (method, segments.as_slice()) {
+        (&Method::GET, ["status"]) => Some(Command::Status),
+        (&Method::POST, ["pause"]) => Some(Command::Pause(None)),
+        (&Method::POST, ["resume"]) => Some(Command::Resume(None)),
+        (&Method::POST, ["channels", name]) => Some(Command::Join(channel(name)?)),
+        (&Method::DELETE, ["channels", name]) => Some(Command::Part(channel(name)?)),
+        (&Method::POST, ["channels", name, "pause"]) => Some(Command::Pause(Some(channel(name)?))),
+        (&Method::POST, ["channels", name, "resume"]) => {
+            Some(Command::Resume(Some(channel(name)?)))
+        }
+        (&Method::POST, ["channels", name, "play"]) => Some(Command::Play(channel(name)?)),
+        _ => None,
+    }
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::Status;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn routes_requests_to_commands() {
+        assert_eq!(route(&Method::GET, "/status"), Some(Command::Status));
+        assert_eq!(
+            route(&Method::POST, "/channels/Marbles"),
+            Some(Command::Join("marbles".into()))
+        );
+        assert_eq!(
+            route(&Method::DELETE, "/channels/marbles/"),
+            Some(Command::Part("marbles".into()))
+        );
+        assert_eq!(
+            route(&Method::POST, "/channels/marbles/play"),
+            Some(Command::Play("marbles".into()))
+        );
+        assert_eq!(route(&Method::POST, "/pause"), Some(Command::Pause(None)));
+        assert_eq!(route(&Method::GET, "/channels/marbles/play"), None);
+        assert_eq!(route(&Method::GET, "/"), None);
+    }
+
+    async fn get(address: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(format!("{}\r\nConnection: close\r\n\r\n", request).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn answers_with_the_message_loop_reply() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let address = listener.local_addr().unwrap();
+        let (requests, mut incoming_requests) = mpsc::unbounded_channel();
+        let metrics = Metrics::new();
+        metrics.channel("marbles").messages.inc();
+        tokio::spawn(serve(listener, requests, metrics));
+        tokio::spawn(async move {
+            while let Some(ControlRequest { command, reply }) = incoming_requests.recv().await {
+                let _ = reply.send(match command {
+                    Command::Status => Reply::Status(Status {
+                        healthy: true,
+                        channels: Vec::new(),
+                    }),
+                    Command::Part(channel) => Reply::NotFound(format!("not in {}", channel)),
+                    _ => Reply::Done("done".to_owned()),
+                });
+            }
+        });
+
+        let status = get(address, "GET /status HTTP/1.1").await;
+        assert!(status.starts_with("HTTP/1.1 200 OK"));
+        assert!(status.ends_with(r#"{"channels":[],"healthy":true}"#));
+
+        let part = get(address, "DELETE /channels/marbles HTTP/1.1").await;
+        assert!(part.starts_with("HTTP/1.1 404 Not Found"));
+        assert!(part.ends_with(r#"{"error":"not in marbles"}"#));
+
+        let metrics = get(address, "GET /metrics HTTP/1.1").await;
+        assert!(metrics.starts_with("HTTP/1.1 200 OK"));
+        assert!(metrics.contains("marblejoiner_messages_total{channel=\"marbles\"} 1"));
+
+        let unknown = get(address, "GET /nothing HTTP/1.1").await;
+        assert!(unknown.starts_with("HTTP/1.1 404 Not Found"));
+    }
+}
@@ -0,0 +1,1807 @@
+//! The engine joining marbles on stream races for you: it watches the chat of every channel for
+//! enough people wanting to play and then joins them. The chat connection is pluggable through
+//! the traits in `chat`, so the engine runs on Twitch as well as in memory.
+
+pub mod chat;
+pub mod clock;
+pub mod config;
+pub mod control;
+#[cfg(test)]
+mod end_to_end;
+#[cfg(test)]
+mod fake_irc;
+pub mod http;
+pub mod limits;
+mod lobby;
+pub mod metrics;
+pub mod recorder;
+pub mod replay;
+pub mod supervisor;
+mod timing;
+pub mod transport;
+pub mod tune;
+
+use chat::{ChatEvent, ChatMessage, ChatSink, ChatSource};
+use chrono::{DateTime, Utc};
+use clock::Clock;
+use config::{
+    normalize_channel, ChannelConfig, ChannelParams, Config, ConfigError, TriggerMode,
+    TriggerPolicy,
+};
+use control::{ChannelStatus, Command, ControlRequest, Reply, SendRecord, Status, TriggerRecord};
+use limits::{RoomState, SendLimiter, Withheld};
+use lobby::Lobby;
+use metrics::{ChannelMetrics, Metrics};
+use recorder::Recorder;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::ops::Add;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use supervisor::JoinSupervisor;
+use timing::JoinTiming;
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+
+#[derive(Debug)]
+struct ChannelMarbleState {
+    login: String,
+    params: ChannelParams,
+    buffer: Vec<Option<(usize, String)>>,
+    current_position: usize,
+    recent_plays: VecDeque<(DateTime<Utc>, usize, String)>,
+    next_play: Instant,
+    announced_at: Option<Instant>,
+    pending_play: Option<JoinHandle<()>>,
+    /// When the last play is sent, it counts as pending until then.
+    play_due: Option<Instant>,
+    paused: bool,
+    last_trigger: Option<TriggerRecord>,
+    /// Written by the task sending the play once it knows how that went.
+    last_send: Arc<Mutex<Option<SendRecord>>>,
+    /// When the first of the currently buffered trigger matches came in.
+    first_match_at: Option<Instant>,
+    /// Shared with the task sending the play, which checks the lobby is still open.
+    lobby: Arc<Mutex<Lobby>>,
+    /// The chat modes, shared with the task sending the play.
+    room: Arc<Mutex<RoomState>>,
+    join_timing: JoinTiming,
+    metrics: ChannelMetrics,
+    clock: Clock,
+    span: Span,
+}
+
+/// A play the trigger logic decided on, to be sent once `wait` has elapsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Play {
+    response: String,
+    wait: Duration,
+    /// When the first message that led to this play came in, if any.
+    first_match_at: Option<Instant>,
+}
+
+/// What the trigger logic made of a chat message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Decision {
+    /// The sender is ignored.
+    Ignored,
+    /// Neither a trigger match nor a race announcement.
+    NoMatch,
+    Paused,
+    /// Not enough users matched yet, or the trigger policy is still missing the announcement.
+    BelowThreshold,
+    Cooldown,
+    /// Triggered, but the lobby will have closed by the time the play would be sent.
+    LobbyClosed,
+    /// Triggered while a play was still pending, so it was coalesced into that one.
+    PlayPending,
+    Play(Play),
+}
+
+impl Decision {
+    fn reason(self: &Decision) -> &'static str {
+        match self {
+            Decision::Ignored => "ignored",
+            Decision::NoMatch => "no_match",
+            Decision::Paused => "paused",
+            Decision::BelowThreshold => "below_threshold",
+            Decision::Cooldown => "cooldown",
+            Decision::LobbyClosed => "lobby_closed",
+            Decision::PlayPending => "play_pending",
+            Decision::Play(_) => "play",
+        }
+    }
+}
+
+#[cfg(test)]
+impl Decision {
+    fn into_play(self: Decision) -> Option<Play> {
+        match self {
+            Decision::Play(play) => Some(play),
+            _ => None,
+        }
+    }
+}
+
+/// Resolves the settings of a channel, both for the channels given at startup and those joined
+/// later on.
+pub struct ChannelFactory {
+    pub config: Config,
+    /// Settings taking precedence over the config file, e.g. from the command line.
+    pub overrides: ChannelConfig,
+    /// Who the engine plays as, to ignore its own messages if asked to along with those of the
+    /// accounts in the config.
+    pub login: String,
+    pub metrics: Metrics,
+    pub clock: Clock,
+}
+
+impl ChannelFactory {
+    pub fn resolve(self: &ChannelFactory, channel: &str) -> Result<ChannelParams, ConfigError> {
+        let mut params = self.config.resolve(channel, &self.overrides)?;
+        if params.ignore_own {
+            params.ignored_users.push(self.login.to_lowercase());
+            params
+                .ignored_users
+                .extend(self.config.account.keys().cloned());
+        }
+        Ok(params)
+    }
+
+    fn create(self: &ChannelFactory, channel: String) -> Result<ChannelMarbleState, ConfigError> {
+        let params = self.resolve(&channel)?;
+        let metrics = self.metrics.channel(&channel);
+        Ok(ChannelMarbleState::new(
+            channel,
+            params,
+            metrics,
+            self.clock.clone(),
+        ))
+    }
+}
+
+#[cfg(test)]
+impl ChannelFactory {
+    /// The defaults with `overrides` on top, on a virtual clock, for the anonymous login.
+    pub(crate) fn for_test(overrides: ChannelConfig) -> ChannelFactory {
+        ChannelFactory {
+            config: Config::default(),
+            overrides,
+            login: "justinfan12345".to_owned(),
+            metrics: Metrics::new(),
+            clock: Clock::new_virtual(),
+        }
+    }
+}
+
+/// An account the engine plays as through its own connection. Once any are added, plays go out
+/// through them instead of the sink the engine watches chat on.
+pub struct Account<S: ChatSink> {
+    pub login: String,
+    pub sink: S,
+    /// Channels it plays in, every channel if empty.
+    pub channels: Vec<String>,
+    /// Least time between two of its plays in a channel, on top of the channel's cooldown.
+    pub delay: Duration,
+    /// How long after a play is due it sends it, so accounts don't all post at the same instant.
+    pub send_offset: Duration,
+    /// To be fed the `USERSTATE`s of the account's connection.
+    pub limiter: SendLimiter,
+}
+
+impl<S: ChatSink> Account<S> {
+    fn plays_in(self: &Account<S>, channel: &str) -> bool {
+        self.channels.is_empty() || self.channels.iter().any(|login| login == channel)
+    }
+}
+
+struct AccountState<S: ChatSink> {
+    account: Account<S>,
+    /// When the account may play again, by channel.
+    next_play: HashMap<String, Instant>,
+}
+
+/// Who a single play is sent as.
+struct Sender<S: ChatSink> {
+    login: String,
+    sink: S,
+    offset: Duration,
+    limiter: SendLimiter,
+}
+
+/// Every channel's trigger logic together with keeping the channels joined, driven by events
+/// from a `ChatSource` and commands from the console or the HTTP API.
+pub struct Engine<S: ChatSink> {
+    marble_states: HashMap<String, ChannelMarbleState>,
+    supervisor: JoinSupervisor,
+    channel_factory: ChannelFactory,
+    sink: S,
+    /// Fed the `USERSTATE`s of the watching connection.
+    limiter: SendLimiter,
+    accounts: Vec<AccountState<S>>,
+    recorder: Option<Recorder>,
+    health_file: Option<PathBuf>,
+}
+
+impl<S: ChatSink> Engine<S> {
+    pub fn new(channel_factory: ChannelFactory, sink: S) -> Engine<S> {
+        Engine {
+            marble_states: HashMap::new(),
+            supervisor: JoinSupervisor::new(&[]),
+            channel_factory,
+            sink,
+            limiter: SendLimiter::default(),
+            accounts: Vec::new(),
+            recorder: None,
+            health_file: None,
+        }
+    }
+
+    /// The limiter of the watching connection, for an account playing through it.
+    pub fn limiter(self: &Engine<S>) -> SendLimiter {
+        self.limiter.clone()
+    }
+
+    /// Plays as `account` too, or instead of the watching sink if it's the first one.
+    pub fn add_account(self: &mut Engine<S>, account: Account<S>) {
+        info!(account = %account.login, send_offset = ?account.send_offset, "playing as account");
+        self.accounts.push(AccountState {
+            account,
+            next_play: HashMap::new(),
+        });
+    }
+
+    /// Records every chat message along with what the engine made of it.
+    pub fn set_recorder(self: &mut Engine<S>, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Keeps `path` updated with the connection health.
+    pub fn set_health_file(self: &mut Engine<S>, path: PathBuf) {
+        self.health_file = Some(path);
+    }
+
+    /// Starts watching `channel`, its `JOIN` goes out on the next check. The accounts playing in
+    /// it join it right away, so add them first.
+    pub fn add_channel(self: &mut Engine<S>, channel: String) -> Result<(), ConfigError> {
+        let marble_state = self.channel_factory.create(channel.to_owned())?;
+        self.supervisor.add(&channel);
+        self.marble_states.insert(channel.to_owned(), marble_state);
+        for state in self.accounts.iter() {
+            if state.account.plays_in(&channel) {
+                if let Err(error) = state.account.sink.join(channel.to_owned()) {
+                    error!(
+                        account = %state.account.login,
+                        channel = %channel,
+                        "could not join: {}",
+                        error
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs until `source` is closed.
+    pub async fn run(
+        mut self: Engine<S>,
+        mut source: impl ChatSource,
+        mut requests: mpsc::UnboundedReceiver<ControlRequest>,
+    ) {
+        let mut check_interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                event = source.next_event() => {
+                    let Some(event) = event else { break };
+                    self.handle_event(&event);
+                }
+                _ = check_interval.tick() => self.supervisor.check(&self.sink).await,
+                Some(request) = requests.recv() => {
+                    let outcome = self.handle_command(request.command);
+                    let _ = request.reply.send(outcome);
+                }
+            }
+
+            if self.supervisor.take_changed() {
+                if let Some(path) = &self.health_file {
+                    write_health_file(path, &self.supervisor);
+                }
+            }
+        }
+    }
+
+    pub fn handle_event(self: &mut Engine<S>, event: &ChatEvent) {
+        self.supervisor.on_event(event);
+        self.limiter.on_event(event);
+        if let ChatEvent::RoomState(channel, update) = event {
+            if let Some(marble_state) = channel_state(&mut self.marble_states, channel) {
+                let mut room = marble_state.room.lock().unwrap();
+                room.apply(update);
+                info!(parent: &marble_state.span, room = ?*room, "chat modes");
+            }
+        }
+        if let ChatEvent::Message(message) = event {
+            if let Some(marble_state) = channel_state(&mut self.marble_states, &message.channel) {
+                let decision = marble_state.process_message(message);
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.record(message, &decision, marble_state.buffered_plays());
+                }
+                if let Decision::Play(play) = decision {
+                    match senders(
+                        &mut self.accounts,
+                        &self.sink,
+                        &self.limiter,
+                        &self.channel_factory,
+                        marble_state,
+                    ) {
+                        Ok(senders) => marble_state.schedule_play(senders, play),
+                        Err(_) => marble_state.withhold_play(),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Applies a console or HTTP command to the running state and describes the outcome.
+    /// Channels keep their state, including the cooldown, as long as they aren't parted.
+    pub fn handle_command(self: &mut Engine<S>, command: Command) -> Reply {
+        match command {
+            Command::Join(channel) => {
+                if self.marble_states.contains_key(&channel) {
+                    return Reply::Invalid(format!("already in {}", channel));
+                }
+                match self.add_channel(channel.to_owned()) {
+                    Ok(()) => {
+                        info!(channel = %channel, "joining channel");
+                        Reply::Done(format!("joining {}", channel))
+                    }
+                    Err(error) => Reply::Invalid(format!("could not join {}: {}", channel, error)),
+                }
+            }
+            Command::Part(channel) => match self.marble_states.remove(&channel) {
+                Some(mut marble_state) => {
+                    info!(channel = %channel, "parting channel");
+                    marble_state.pause();
+                    self.supervisor.remove(&channel);
+                    self.channel_factory.metrics.remove_channel(&channel);
+                    self.sink.part(channel.to_owned());
+                    for state in self.accounts.iter() {
+                        if state.account.plays_in(&channel) {
+                            state.account.sink.part(channel.to_owned());
+                        }
+                    }
+                    Reply::Done(format!("parted {}", channel))
+                }
+                None => Reply::NotFound(format!("not in {}", channel)),
+            },
+            Command::Pause(channel) => set_paused(&mut self.marble_states, channel, true),
+            Command::Resume(channel) => set_paused(&mut self.marble_states, channel, false),
+            Command::Status => {
+                let mut channels: Vec<ChannelStatus> = self
+                    .marble_states
+                    .values()
+                    .map(|marble_state| {
+                        let health = self
+                            .supervisor
+                            .health(&marble_state.login)
+                            .map_or_else(|| "unknown".to_owned(), ToString::to_string);
+                        marble_state.status(health)
+                    })
+                    .collect();
+                channels.sort_by(|a, b| a.channel.cmp(&b.channel));
+                Reply::Status(Status {
+                    healthy: self.supervisor.is_healthy(),
+                    channels,
+                })
+            }
+            Command::Play(channel) => match self.marble_states.get_mut(&channel) {
+                Some(marble_state) => match marble_state.force_play() {
+                    Some(play) => match senders(
+                        &mut self.accounts,
+                        &self.sink,
+                        &self.limiter,
+                        &self.channel_factory,
+                        marble_state,
+                    ) {
+                        Ok(senders) => {
+                            marble_state.schedule_play(senders, play);
+                            Reply::Done(format!("playing in {}", channel))
+                        }
+                        Err(reason) => {
+                            marble_state.withhold_play();
+                            Reply::Invalid(format!(
+                                "chat in {} wouldn't take a play: {}",
+                                channel, reason
+                            ))
+                        }
+                    },
+                    None => Reply::Invalid(format!("a play is already pending in {}", channel)),
+                },
+                None => Reply::NotFound(format!("not in {}", channel)),
+            },
+            Command::Set {
+                channel,
+                setting,
+                value,
+            } => match self.marble_states.get_mut(&channel) {
+                Some(marble_state) => match marble_state.set(&setting, &value) {
+                    Ok(()) => {
+                        info!(channel = %channel, setting = %setting, value = %value, "setting changed");
+                        Reply::Done(format!("set {} to {} in {}", setting, value, channel))
+                    }
+                    Err(error) => Reply::Invalid(error),
+                },
+                None => Reply::NotFound(format!("not in {}", channel)),
+            },
+        }
+    }
+}
+
+/// Who to send a play in the channel of `marble_state` as: every account playing there whose own
+/// cooldown is over, starting it anew, or the watching sink and its `limiter` as long as there
+/// are no accounts. Accounts the chat modes would reject are left out without starting their
+/// cooldown, and if that leaves nobody, the reason is returned instead.
+fn senders<S: ChatSink>(
+    accounts: &mut [AccountState<S>],
+    sink: &S,
+    limiter: &SendLimiter,
+    channel_factory: &ChannelFactory,
+    marble_state: &ChannelMarbleState,
+) -> Result<Vec<Sender<S>>, &'static str> {
+    let channel = marble_state.login.as_str();
+    let room = marble_state.room.lock().unwrap().clone();
+    let withhold = |login: &str, reason: &'static str| {
+        marble_state.metrics.plays_withheld.inc();
+        warn!(parent: &marble_state.span, account = %login, reason, "chat wouldn't take the play, not sending it");
+        reason
+    };
+    if accounts.is_empty() {
+        if let Some(reason) = limiter.rejection(channel, &room) {
+            return Err(withhold(&channel_factory.login, reason));
+        }
+        return Ok(vec![Sender {
+            login: channel_factory.login.to_owned(),
+            sink: sink.clone(),
+            offset: Duration::ZERO,
+            limiter: limiter.clone(),
+        }]);
+    }
+    let now = channel_factory.clock.now();
+    let mut rejected = None;
+    let senders: Vec<Sender<S>> = accounts
+        .iter_mut()
+        .filter(|state| state.account.plays_in(channel))
+        .filter_map(|state| {
+            if state
+                .next_play
+                .get(channel)
+                .is_some_and(|next_play| *next_play > now)
+            {
+                debug!(
+                    account = %state.account.login,
+                    channel = %channel,
+                    "account cooldown active"
+                );
+                return None;
+            }
+            if let Some(reason) = state.account.limiter.rejection(channel, &room) {
+                rejected = Some(withhold(&state.account.login, reason));
+                return None;
+            }
+            state
+                .next_play
+                .insert(channel.to_owned(), now.add(state.account.delay));
+            Some(Sender {
+                login: state.account.login.to_owned(),
+                sink: state.account.sink.clone(),
+                offset: state.account.send_offset,
+                limiter: state.account.limiter.clone(),
+            })
+        })
+        .collect();
+    if senders.is_empty() {
+        if let Some(reason) = rejected {
+            return Err(reason);
+        }
+        info!(channel = %channel, "no account can play right now");
+    }
+    Ok(senders)
+}
+
+fn write_health_file(path: &Path, supervisor: &JoinSupervisor) {
+    if let Err(error) = fs::write(path, supervisor.report()) {
+        warn!(path = %path.display(), "could not write health file: {}", error);
+    }
+}
+
+/// Pauses or resumes `channel`, or every channel if none is given.
+fn set_paused(
+    marble_states: &mut HashMap<String, ChannelMarbleState>,
+    channel: Option<String>,
+    paused: bool,
+) -> Reply {
+    let channels: Vec<&mut ChannelMarbleState> = match &channel {
+        Some(channel) => match marble_states.get_mut(channel) {
+            Some(marble_state) => vec![marble_state],
+            None => return Reply::NotFound(format!("not in {}", channel)),
+        },
+        None => marble_states.values_mut().collect(),
+    };
+    for marble_state in channels {
+        if paused {
+            marble_state.pause();
+        } else {
+            marble_state.resume();
+        }
+    }
+    Reply::Done(format!(
+        "{} {}",
+        if paused { "paused" } else { "resumed" },
+        channel.as_deref().unwrap_or("all channels")
+    ))
+}
+
+/// Looks up the state of `channel`, ignoring messages from channels we never joined, e.g. the
+/// other channels of a shared chat.
+fn channel_state<'a>(
+    marble_states: &'a mut HashMap<String, ChannelMarbleState>,
+    channel: &str,
+) -> Option<&'a mut ChannelMarbleState> {
+    let marble_state = marble_states.get_mut(&normalize_channel(channel));
+    if marble_state.is_none() {
+        warn!(channel = %channel, "ignoring message from unknown channel");
+    }
+    marble_state
+}
+
+/// A play on its way to chat, shared by the tasks sending it as each account.
+struct PlayTask {
+    channel: String,
+    response: String,
+    wait: Duration,
+    first_match_at: Option<Instant>,
+    last_send: Arc<Mutex<Option<SendRecord>>>,
+    metrics: ChannelMetrics,
+    lobby: Arc<Mutex<Lobby>>,
+    room: Arc<Mutex<RoomState>>,
+    params: ChannelParams,
+    clock: Clock,
+}
+
+impl PlayTask {
+    /// Sends the play as `sender` once its offset past the wait from `started_at` is over, unless
+    /// the lobby closed in the meantime or chat wouldn't take it.
+    async fn send_as<S: ChatSink>(
+        self: Arc<PlayTask>,
+        sender: Sender<S>,
+        started_at: tokio::time::Instant,
+    ) {
+        let span = info_span!("account", account = %sender.login);
+        let due = self.wait + sender.offset;
+        tokio::time::sleep_until(started_at + due).await;
+        // a play sent right away, e.g. a forced one, can't be late
+        if !due.is_zero()
+            && !self
+                .lobby
+                .lock()
+                .unwrap()
+                .is_open(self.clock.now(), &self.params)
+        {
+            self.metrics.plays_late.inc();
+            warn!(
+                parent: &span,
+                response = %self.response,
+                "lobby closed in the meantime, not sending play"
+            );
+            return;
+        }
+        if self.params.dry_run {
+            info!(parent: &span, response = %self.response, "dry run, not sending play");
+            return;
+        }
+        loop {
+            let room = self.room.lock().unwrap().clone();
+            let withheld = match sender
+                .limiter
+                .acquire(&self.channel, &room, self.clock.now())
+            {
+                Ok(()) => break,
+                Err(withheld) => withheld,
+            };
+            let reason = match withheld {
+                Withheld::Deferred(delay, reason) => {
+                    let lobby_closes_at = self.lobby.lock().unwrap().closes_at(&self.params);
+                    if lobby_closes_at
+                        .is_some_and(|closes_at| closes_at > self.clock.now().add(delay))
+                    {
+                        info!(parent: &span, reason, delay = ?delay, "deferring play");
+                        tokio::time::sleep(delay).await;
+                        continue;
+                    }
+                    reason
+                }
+                Withheld::Rejected(reason) => reason,
+            };
+            self.metrics.plays_withheld.inc();
+            warn!(
+                parent: &span,
+                reason,
+                response = %self.response,
+                "chat wouldn't take the play in time, not sending it"
+            );
+            return;
+        }
+        say_play(
+            &sender.sink,
+            self.channel.to_owned(),
+            self.response.to_owned(),
+            &self.last_send,
+            &self.metrics,
+            self.first_match_at,
+            &self.clock,
+        )
+        .instrument(span)
+        .await;
+    }
+}
+
+/// Sends the play and keeps track of how that went. `first_match_at` is when the first message
+/// that led to this play came in on `clock`, if any.
+async fn say_play<S: ChatSink>(
+    sink: &S,
+    channel: String,
+    response: String,
+    last_send: &Mutex<Option<SendRecord>>,
+    metrics: &ChannelMetrics,
+    first_match_at: Option<Instant>,
+    clock: &Clock,
+) {
+    let result = sink.say(channel, response.to_owned()).await;
+    match &result {
+        Ok(()) => {
+            info!(response = %response, "play sent");
+            metrics.plays_sent.inc();
+            if let Some(first_match_at) = first_match_at {
+                metrics.reaction_latency.observe(
+                    clock
+                        .now()
+                        .saturating_duration_since(first_match_at)
+                        .as_secs_f64(),
+                );
+            }
+        }
+        Err(error) => {
+            error!(response = %response, "say failed: {}", error);
+            metrics.say_errors.inc();
+        }
+    }
+    *last_send.lock().unwrap() = Some(SendRecord {
+        at: Utc::now(),
+        response,
+        error: result.err().map(|error| error.to_string()),
+    });
+}
+
+impl ChannelMarbleState {
+    fn new(
+        login: String,
+        params: ChannelParams,
+        metrics: ChannelMetrics,
+        clock: Clock,
+    ) -> ChannelMarbleState {
+        ChannelMarbleState {
+            span: info_span!("channel", channel = %login),
+            login,
+            buffer: vec![None; params.buffer_size],
+            params,
+            current_position: 0,
+            recent_plays: VecDeque::new(),
+            next_play: clock.now(),
+            announced_at: None,
+            pending_play: None,
+            play_due: None,
+            paused: false,
+            last_trigger: None,
+            last_send: Arc::new(Mutex::new(None)),
+            first_match_at: None,
+            lobby: Arc::new(Mutex::new(Lobby::default())),
+            room: Arc::new(Mutex::new(RoomState::default())),
+            join_timing: JoinTiming::default(),
+            metrics,
+            clock,
+        }
+    }
+
+    /// Runs a chat message through the trigger logic, deciding whether it leads to a play.
+    fn process_message(self: &mut ChannelMarbleState, message: &ChatMessage) -> Decision {
+        let span = self.span.clone();
+        let _entered = span.enter();
+        debug!(sender = %message.sender, text = %message.text, "message");
+        self.metrics.messages.inc();
+        // before the ring buffer moves on, so a flood of ignored messages can't push plays out
+        if self.is_ignored(message) {
+            return Decision::Ignored;
+        }
+        self.current_position = (self.current_position + 1) % self.params.buffer_size;
+        let matched_trigger = self.matching_trigger(&message.text);
+        self.buffer[self.current_position] =
+            matched_trigger.map(|trigger| (trigger, message.sender_id.to_owned()));
+        self.record_play(message, matched_trigger);
+        if matched_trigger.is_some() {
+            self.metrics.trigger_matches.inc();
+            self.first_match_at.get_or_insert(self.clock.now());
+            self.lobby
+                .lock()
+                .unwrap()
+                .on_match(self.clock.now(), self.params.window);
+            self.join_timing
+                .on_match(message.timestamp, &message.sender_id, &self.params);
+        }
+        let buffered_plays = self.buffered_plays();
+        if buffered_plays == 0 {
+            self.first_match_at = None;
+        }
+        self.metrics.buffer_fill.set(buffered_plays as i64);
+        let is_announcement = self.is_announcement(message);
+        if is_announcement {
+            info!(announcer = %message.sender, "race announced");
+            self.announced_at = Some(self.clock.now());
+        }
+        if matched_trigger.is_none() && !is_announcement {
+            return Decision::NoMatch;
+        }
+        if self.paused {
+            debug!("paused, not triggering");
+            return Decision::Paused;
+        }
+        let Some(trigger) = self.triggered() else {
+            return Decision::BelowThreshold;
+        };
+        self.metrics.threshold_hits.inc();
+        let lobby_closes_at = self.open_lobby();
+        if !self.is_time_to_play() {
+            self.metrics.plays_suppressed.inc();
+            debug!(
+                remaining = ?self.next_play.saturating_duration_since(self.clock.now()),
+                "cooldown active"
+            );
+            return Decision::Cooldown;
+        }
+        let wait = match self.join_timing.wait(message.timestamp, &self.params) {
+            Some(wait) => {
+                debug!(wait = ?wait, "using the learned join time");
+                wait
+            }
+            None => self.params.wait,
+        };
+        if lobby_closes_at <= self.clock.now().add(wait) {
+            self.metrics.plays_late.inc();
+            info!("lobby closes before the wait is over, not playing");
+            return Decision::LobbyClosed;
+        }
+        if self.is_play_pending() {
+            info!("play already scheduled, ignoring additional trigger");
+            return Decision::PlayPending;
+        }
+
+        let pattern = self.params.triggers[trigger].pattern.as_str();
+        info!(trigger = %pattern, announced = self.is_announced(), "threshold reached");
+        self.last_trigger = Some(TriggerRecord {
+            at: message.timestamp,
+            pattern: Some(pattern.to_owned()),
+            announced: self.is_announced(),
+        });
+        self.next_play = self.clock.now().add(self.params.delay);
+        self.clear_buffer();
+        self.announced_at = None;
+        let response = self.params.triggers[trigger].response.to_owned();
+        Decision::Play(self.start_play(response, wait))
+    }
+
+    /// Plays the first trigger's response right away, starting the cooldown like a regular play.
+    /// Leaves everything as it is while a play is still pending.
+    fn force_play(self: &mut ChannelMarbleState) -> Option<Play> {
+        if self.is_play_pending() {
+            info!(parent: &self.span, "play already scheduled, not forcing another one");
+            return None;
+        }
+        info!(parent: &self.span, "play forced");
+        self.last_trigger = Some(TriggerRecord {
+            at: Utc::now(),
+            pattern: None,
+            announced: self.is_announced(),
+        });
+        self.next_play = self.clock.now().add(self.params.delay);
+        self.clear_buffer();
+        self.announced_at = None;
+        self.first_match_at = None;
+        self.lobby.lock().unwrap().open(self.clock.now());
+        let response = self.params.triggers[0].response.to_owned();
+        Some(self.start_play(response, Duration::ZERO))
+    }
+
+    /// Decides on sending `response` once `wait` has elapsed. The callers make sure no play is
+    /// pending before changing any state for this one.
+    fn start_play(self: &mut ChannelMarbleState, response: String, wait: Duration) -> Play {
+        self.play_due = Some(self.clock.now().add(wait));
+        Play {
+            response,
+            wait,
+            first_match_at: self.first_match_at.take(),
+        }
+    }
+
+    /// Opens a lobby unless one is open already, dating it back to the announcement if there was
+    /// one, and returns when it closes at the latest.
+    fn open_lobby(self: &mut ChannelMarbleState) -> Instant {
+        let now = self.clock.now();
+        let mut lobby = self.lobby.lock().unwrap();
+        if !lobby.is_open(now, &self.params) {
+            let opened_at = match self.announced_at {
+                Some(announced_at) if self.is_announced() => announced_at,
+                _ => now,
+            };
+            debug!(ago = ?now.saturating_duration_since(opened_at), "lobby opened");
+            lobby.open(opened_at);
+        }
+        lobby.closes_at(&self.params).unwrap()
+    }
+
+    /// Returns the trigger whose response should be sent, if any. Announcements are answered with
+    /// the first trigger's response unless the crowd already settled on another one.
+    fn triggered(self: &ChannelMarbleState) -> Option<usize> {
+        let reached = self.reached_trigger();
+        match self.params.trigger_policy {
+            TriggerPolicy::Either if self.is_announced() => Some(reached.unwrap_or(0)),
+            TriggerPolicy::Either => reached,
+            TriggerPolicy::Both if self.is_announced() => reached,
+            TriggerPolicy::Both => None,
+        }
+    }
+
+    fn matching_trigger(self: &ChannelMarbleState, message: &str) -> Option<usize> {
+        self.params
+            .triggers
+            .iter()
+            .position(|trigger| trigger.pattern.is_match(message))
+    }
+
+    fn is_ignored(self: &ChannelMarbleState, message: &ChatMessage) -> bool {
+        self.params.ignored_users.contains(&message.sender)
+    }
+
+    fn is_announcement(self: &ChannelMarbleState, message: &ChatMessage) -> bool {
+        self.params.announcers.contains(&message.sender)
+            && self
+                .params
+                .announcement_patterns
+                .iter()
+                .any(|pattern| pattern.is_match(&message.text))
+    }
+
+    /// An announcement stays valid for one `delay`, which is about as long as a race cycle.
+    fn is_announced(self: &ChannelMarbleState) -> bool {
+        self.announced_at
+            .is_some_and(|announced_at| announced_at.add(self.params.delay) > self.clock.now())
+    }
+
+    /// Posts the play message as every sender once its wait and the sender's offset have
+    /// elapsed, without holding up the message loop. A send the chat modes or the rate limit
+    /// hold back is deferred while the lobby stays open and dropped otherwise.
+    fn schedule_play<S: ChatSink>(
+        self: &mut ChannelMarbleState,
+        senders: Vec<Sender<S>>,
+        play: Play,
+    ) {
+        let Play {
+            response,
+            wait,
+            first_match_at,
+        } = play;
+        let task = Arc::new(PlayTask {
+            channel: self.login.to_owned(),
+            response,
+            wait,
+            first_match_at,
+            last_send: self.last_send.clone(),
+            metrics: self.metrics.clone(),
+            lobby: self.lobby.clone(),
+            room: self.room.clone(),
+            params: self.params.clone(),
+            clock: self.clock.clone(),
+        });
+        info!(parent: &self.span, wait = ?wait, "wait started");
+        self.pending_play = Some(tokio::spawn(
+            async move {
+                let started_at = tokio::time::Instant::now();
+                // every account on its own, so one held back by slow mode or the rate limit
+                // doesn't hold back the others; aborting this aborts them all
+                let mut sends = JoinSet::new();
+                for sender in senders {
+                    sends.spawn(
+                        task.clone()
+                            .send_as(sender, started_at)
+                            .instrument(Span::current()),
+                    );
+                }
+                while sends.join_next().await.is_some() {}
+            }
+            .instrument(self.span.clone()),
+        ));
+    }
+
+    /// Takes back the cooldown of a play nobody could send, so it doesn't hold back the next one.
+    fn withhold_play(self: &mut ChannelMarbleState) {
+        self.next_play = self.clock.now();
+        self.play_due = None;
+    }
+
+    fn is_play_pending(self: &ChannelMarbleState) -> bool {
+        self.play_due.is_some_and(|due| due > self.clock.now())
+    }
+
+    fn is_time_to_play(self: &ChannelMarbleState) -> bool {
+        self.next_play <= self.clock.now()
+    }
+
+    /// Keeps track of who matched which trigger when, dropping everything that fell out of the
+    /// window.
+    fn record_play(
+        self: &mut ChannelMarbleState,
+        message: &ChatMessage,
+        matched_trigger: Option<usize>,
+    ) {
+        let now = message.timestamp;
+        if let Some(trigger) = matched_trigger {
+            self.recent_plays
+                .push_back((now, trigger, message.sender_id.to_owned()));
+        }
+        let window_start = now - chrono::Duration::from_std(self.params.window).unwrap();
+        while let Some((timestamp, _, _)) = self.recent_plays.front() {
+            if *timestamp >= window_start {
+                break;
+            }
+            self.recent_plays.pop_front();
+        }
+    }
+
+    /// Returns the first trigger matched by at least `treshhold` users. Counts distinct users
+    /// rather than messages, so a single spammer can't trigger on their own.
+    fn reached_trigger(self: &ChannelMarbleState) -> Option<usize> {
+        let matches: HashSet<(usize, &String)> = match self.params.trigger_mode {
+            TriggerMode::Buffer => self
+                .buffer
+                .iter()
+                .flatten()
+                .map(|(trigger, sender)| (*trigger, sender))
+                .collect(),
+            TriggerMode::Window => self
+                .recent_plays
+                .iter()
+                .map(|(_, trigger, sender)| (*trigger, sender))
+                .collect(),
+        };
+        (0..self.params.triggers.len()).find(|trigger| {
+            matches
+                .iter()
+                .filter(|(matched, _)| matched == trigger)
+                .count()
+                >= self.params.treshhold
+        })
+    }
+
+    /// Stops triggering until resumed, dropping a play that is still waiting to be sent.
+    fn pause(self: &mut ChannelMarbleState) {
+        if let Some(pending_play) = self.pending_play.take() {
+            pending_play.abort();
+        }
+        self.play_due = None;
+        self.paused = true;
+        info!(parent: &self.span, "paused");
+    }
+
+    fn resume(self: &mut ChannelMarbleState) {
+        self.paused = false;
+        info!(parent: &self.span, "resumed");
+    }
+
+    fn status(self: &ChannelMarbleState, health: String) -> ChannelStatus {
+        ChannelStatus {
+            channel: self.login.to_owned(),
+            health,
+            paused: self.paused,
+            buffered_plays: self.buffered_plays(),
+            buffer_size: self.params.buffer_size,
+            treshhold: self.params.treshhold,
+            next_play_in: self
+                .next_play
+                .saturating_duration_since(self.clock.now())
+                .as_secs(),
+            last_trigger: self.last_trigger.clone(),
+            last_send: self.last_send.lock().unwrap().clone(),
+            lobby_open: self
+                .lobby
+                .lock()
+                .unwrap()
+                .is_open(self.clock.now(), &self.params),
+        }
+    }
+
+    /// Changes one of the channel's settings while keeping its cooldown and what it buffered so
+    /// far. Settings are named like in the config file.
+    fn set(self: &mut ChannelMarbleState, setting: &str, value: &str) -> Result<(), String> {
+        let invalid = |error: &dyn std::fmt::Display| format!("invalid {}: {}", setting, error);
+        let seconds = |max: u64| {
+            let seconds: u64 = value.parse().map_err(|error| invalid(&error))?;
+            if seconds > max {
+                return Err(invalid(&format!("must be at most {} seconds", max)));
+            }
+            Ok(Duration::from_secs(seconds))
+        };
+        match setting {
+            "buffer_size" => {
+                let buffer_size: usize = value.parse().map_err(|error| invalid(&error))?;
+                if buffer_size == 0 {
+                    return Err(invalid(&"must be at least 1"));
+                }
+                self.buffer.resize(buffer_size, None);
+                self.current_position %= buffer_size;
+                self.params.buffer_size = buffer_size;
+            }
+            "treshhold" => self.params.treshhold = value.parse().map_err(|error| invalid(&error))?,
+            "delay" => self.params.delay = seconds(config::MAX_DELAY)?,
+            "wait" => self.params.wait = seconds(config::MAX_WAIT)?,
+            "window" => self.params.window = seconds(config::MAX_WINDOW)?,
+            "lobby_duration" => self.params.lobby_duration = seconds(config::MAX_LOBBY_DURATION)?,
+            "wait_percentile" => {
+                let wait_percentile: f64 = value.parse().map_err(|error| invalid(&error))?;
+                if !(0.0..=100.0).contains(&wait_percentile) {
+                    return Err(invalid(&"must be between 0 and 100"));
+                }
+                self.params.wait_percentile = Some(wait_percentile);
+            }
+            "wait_jitter" => {
+                let wait_jitter =
+                    Duration::try_from_secs_f64(value.parse().map_err(|error| invalid(&error))?)
+                        .map_err(|error| invalid(&error))?;
+                if wait_jitter > Duration::from_secs(config::MAX_WAIT) {
+                    return Err(invalid(&format!(
+                        "must be at most {} seconds",
+                        config::MAX_WAIT
+                    )));
+                }
+                self.params.wait_jitter = wait_jitter;
+            }
+            "lobby_min_matches" => {
+                self.params.lobby_min_matches = value.parse().map_err(|error| invalid(&error))?
+            }
+            "trigger_mode" => {
+                self.params.trigger_mode =
+                    clap::ValueEnum::from_str(value, true).map_err(|error| invalid(&error))?
+            }
+            "trigger_policy" => {
+                self.params.trigger_policy =
+                    clap::ValueEnum::from_str(value, true).map_err(|error| invalid(&error))?
+            }
+            _ => return Err(format!(
+                "unknown setting {}, use one of buffer_size, treshhold, delay, wait, wait_percentile, wait_jitter, window, lobby_duration, lobby_min_matches, trigger_mode or trigger_policy",
+                setting
+            )),
+        }
+        Ok(())
+    }
+
+    /// How many trigger matches the threshold currently considers.
+    fn buffered_plays(self: &ChannelMarbleState) -> usize {
+        match self.params.trigger_mode {
+            TriggerMode::Buffer => self.buffer.iter().flatten().count(),
+            TriggerMode::Window => self.recent_plays.len(),
+        }
+    }
+
+    fn clear_buffer(self: &mut ChannelMarbleState) {
+        for i in 0..self.buffer.len() {
+            self.buffer[i] = None;
+        }
+        self.recent_plays.clear();
+        self.metrics.buffer_fill.set(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::MemoryChat;
+
+    fn chat_message(channel: &str, sender: &str, text: &str) -> ChatMessage {
+        ChatMessage {
+            timestamp: "2020-07-12T16:32:46.672Z".parse().unwrap(),
+            channel: channel.to_owned(),
+            sender: sender.to_owned(),
+            sender_id: format!("{}-id", sender),
+            text: text.to_owned(),
+        }
+    }
+
+    fn message(channel: &str, sender: &str, text: &str) -> ChatEvent {
+        ChatEvent::Message(chat_message(channel, sender, text))
+    }
+
+    fn engine_with(
+        overrides: ChannelConfig,
+        channels: &[&str],
+    ) -> (Engine<MemoryChat>, MemoryChat) {
+        let (chat, _) = MemoryChat::new();
+        let mut engine = Engine::new(ChannelFactory::for_test(overrides), chat.clone());
+        for channel in channels {
+            engine.add_channel(channel.to_string()).unwrap();
+        }
+        (engine, chat)
+    }
+
+    fn engine(channels: &[&str]) -> Engine<MemoryChat> {
+        engine_with(ChannelConfig::default(), channels).0
+    }
+
+    fn buffered_plays(marble_state: &ChannelMarbleState) -> usize {
+        marble_state.buffer.iter().flatten().count()
+    }
+
+    #[test]
+    fn normalizes_channel_logins() {
+        assert_eq!(normalize_channel("#MarblesOnStream"), "marblesonstream");
+        assert_eq!(normalize_channel(" marbles "), "marbles");
+    }
+
+    #[test]
+    fn dispatches_messages_to_their_channel() {
+        let mut engine = engine(&["marbles", "pixelbypixel"]);
+
+        engine.handle_event(&message("marbles", "someone", "!play"));
+
+        assert_eq!(buffered_plays(&engine.marble_states["marbles"]), 1);
+        assert_eq!(buffered_plays(&engine.marble_states["pixelbypixel"]), 0);
+    }
+
+    #[test]
+    fn ignores_messages_from_unknown_channels() {
+        let mut engine = engine(&["marbles"]);
+
+        engine.handle_event(&message("somewhereelse", "someone", "!play"));
+
+        assert_eq!(engine.marble_states.len(), 1);
+        assert_eq!(buffered_plays(&engine.marble_states["marbles"]), 0);
+    }
+
+    #[test]
+    fn looks_up_channel_state_by_normalized_login() {
+        let mut marble_states = engine(&["marbles"]).marble_states;
+
+        assert!(channel_state(&mut marble_states, "#Marbles").is_some());
+        assert!(channel_state(&mut marble_states, "marbles").is_some());
+        assert!(channel_state(&mut marble_states, "pixelbypixel").is_none());
+    }
+
+    #[test]
+    fn console_commands_change_running_state() {
+        let mut engine = engine(&["marbles"]);
+        let next_play = Instant::now() + Duration::from_secs(60);
+        engine.marble_states.get_mut("marbles").unwrap().next_play = next_play;
+
+        for (command, outcome) in [
+            ("join pixelbypixel", "joining pixelbypixel"),
+            ("set marbles treshhold 7", "set treshhold to 7 in marbles"),
+            (
+                "set marbles treshhold lots",
+                "invalid treshhold: invalid digit found in string",
+            ),
+            ("pause marbles", "paused marbles"),
+            ("part pixelbypixel", "parted pixelbypixel"),
+            ("part pixelbypixel", "not in pixelbypixel"),
+        ] {
+            let command = command.parse().unwrap();
+            assert_eq!(engine.handle_command(command).to_string(), outcome);
+        }
+
+        let marble_state = &engine.marble_states["marbles"];
+        assert_eq!(marble_state.params.treshhold, 7);
+        assert!(marble_state.paused);
+        assert_eq!(marble_state.next_play, next_play);
+        assert_eq!(engine.marble_states.len(), 1);
+        assert_eq!(engine.supervisor.report(), "degraded\nmarbles joining\n");
+    }
+
+    #[test]
+    fn paused_channel_does_not_trigger_but_keeps_buffering() {
+        let mut engine = engine(&["marbles"]);
+        set_paused(&mut engine.marble_states, None, true);
+
+        for sender in ["a", "b", "c", "d", "e"] {
+            engine.handle_event(&message("marbles", sender, "!play"));
+        }
+
+        let marble_state = &engine.marble_states["marbles"];
+        assert!(!marble_state.is_play_pending());
+        assert_eq!(buffered_plays(marble_state), 5);
+    }
+
+    #[test]
+    fn forced_play_starts_cooldown_and_shows_in_status() {
+        let mut engine = engine(&["marbles"]);
+        let marble_state = engine.marble_states.get_mut("marbles").unwrap();
+
+        let play = marble_state.force_play().unwrap();
+
+        let status = marble_state.status("joined".to_owned());
+        assert_eq!(play.wait, Duration::ZERO);
+        assert_eq!(status.next_play_in, 120);
+        assert_eq!(status.last_trigger.unwrap().pattern, None);
+        assert_eq!(status.last_send, None);
+    }
+
+    #[test]
+    fn rejects_settings_longer_than_a_day() {
+        let mut engine = engine(&["marbles"]);
+        let marble_state = engine.marble_states.get_mut("marbles").unwrap();
+
+        assert!(marble_state
+            .set("lobby_duration", "18446744073709551615")
+            .is_err());
+        assert!(marble_state.set("window", "18446744073709551615").is_err());
+        assert!(marble_state.set("wait", "18446744073709551615").is_err());
+        assert!(marble_state.set("delay", "18446744073709551615").is_err());
+        assert!(marble_state.set("wait_jitter", "1e10").is_err());
+        assert!(marble_state.set("lobby_duration", "300").is_ok());
+        assert_eq!(marble_state.params.lobby_duration, Duration::from_secs(300));
+    }
+
+    #[test]
+    fn forced_play_while_one_is_pending_changes_nothing() {
+        let mut engine = engine(&["marbles"]);
+        let marble_state = engine.marble_states.get_mut("marbles").unwrap();
+        let clock = marble_state.clock.clone();
+        for sender in ["a", "b", "c", "d", "e"] {
+            marble_state.process_message(&chat_message("marbles", sender, "!play"));
+        }
+        assert!(marble_state.is_play_pending());
+        clock.advance(Duration::from_secs(1));
+        marble_state.process_message(&chat_message("marbles", "f", "!play"));
+        let next_play = marble_state.next_play;
+
+        assert_eq!(marble_state.force_play(), None);
+
+        assert_eq!(marble_state.next_play, next_play);
+        assert_eq!(buffered_plays(marble_state), 1);
+    }
+
+    #[tokio::test]
+    async fn counts_matches_threshold_hits_and_suppressed_plays() {
+        let mut engine = engine(&["marbles"]);
+
+        for sender in ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"] {
+            engine.handle_event(&message("marbles", sender, "!play"));
+        }
+        engine.handle_event(&message("marbles", "k", "hello"));
+
+        let metrics = &engine.marble_states["marbles"].metrics;
+        assert_eq!(metrics.messages.get(), 11);
+        assert_eq!(metrics.trigger_matches.get(), 10);
+        assert_eq!(metrics.threshold_hits.get(), 2);
+        assert_eq!(metrics.plays_suppressed.get(), 1);
+        assert_eq!(metrics.buffer_fill.get(), 5);
+    }
+
+    fn send(marble_state: &mut ChannelMarbleState, sender: &str, text: &str) -> Option<Play> {
+        marble_state
+            .process_message(&chat_message("marbles", sender, text))
+            .into_play()
+    }
+
+    #[test]
+    fn cooldown_and_pending_play_follow_the_clock() {
+        let mut engine = engine(&["marbles"]);
+        let marble_state = engine.marble_states.get_mut("marbles").unwrap();
+        let clock = marble_state.clock.clone();
+
+        let plays: Vec<Play> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .filter_map(|sender| send(marble_state, sender, "!play"))
+            .collect();
+        assert_eq!(plays.len(), 1);
+        assert_eq!(plays[0].wait, Duration::from_secs(5));
+        assert!(marble_state.is_play_pending());
+        clock.advance(Duration::from_secs(5));
+        assert!(!marble_state.is_play_pending());
+
+        clock.advance(Duration::from_secs(114));
+        for sender in ["f", "g", "h", "i", "j"] {
+            assert_eq!(send(marble_state, sender, "!play"), None);
+        }
+        clock.advance(Duration::from_secs(1));
+        assert!(send(marble_state, "k", "!play").is_some());
+    }
+
+    #[test]
+    fn coalesces_a_trigger_during_the_wait_into_the_pending_play() {
+        let (mut engine, _) = engine_with(
+            ChannelConfig {
+                wait: Some(10),
+                delay: Some(5),
+                ..ChannelConfig::default()
+            },
+            &["marbles"],
+        );
+        let marble_state = engine.marble_states.get_mut("marbles").unwrap();
+        let clock = marble_state.clock.clone();
+
+        let mut decisions: Vec<Decision> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|sender| marble_state.process_message(&chat_message("marbles", sender, "!play")))
+            .collect();
+        let next_play = marble_state.next_play;
+        clock.advance(Duration::from_secs(6));
+        decisions.extend(
+            ["f", "g", "h", "i", "j"].iter().map(|sender| {
+                marble_state.process_message(&chat_message("marbles", sender, "!play"))
+            }),
+        );
+
+        assert!(matches!(decisions[4], Decision::Play(_)));
+        assert_eq!(decisions[9], Decision::PlayPending);
+        // the coalesced trigger neither restarted the cooldown nor dropped what it buffered
+        assert_eq!(marble_state.next_play, next_play);
+        assert_eq!(buffered_plays(marble_state), 5);
+        let plays = decisions
+            .iter()
+            .filter(|decision| matches!(decision, Decision::Play(_)))
+            .count();
+        assert_eq!(plays, 1);
+    }
+
+    #[test]
+    fn an_announcement_alone_plays_under_either_policy() {
+        let (mut engine, _) = engine_with(
+            ChannelConfig {
+                announcers: Some(vec!["MarblesBot".to_owned()]),
+                trigger_policy: Some(TriggerPolicy::Either),
+                ..ChannelConfig::default()
+            },
+            &["marbles"],
+        );
+        let marble_state = engine.marble_states.get_mut("marbles").unwrap();
+
+        assert_eq!(
+            marble_state.process_message(&chat_message("marbles", "someone", "Type !play to join")),
+            Decision::NoMatch
+        );
+        let play = marble_state
+            .process_message(&chat_message("marbles", "marblesbot", "Type !play to join"))
+            .into_play()
+            .unwrap();
+        assert_eq!(play.response, "!play >:(");
+    }
+
+    #[test]
+    fn window_mode_counts_the_users_within_the_window() {
+        let (mut engine, _) = engine_with(
+            ChannelConfig {
+                trigger_mode: Some(TriggerMode::Window),
+                window: Some(10),
+                treshhold: Some(3),
+                wait: Some(0),
+                ..ChannelConfig::default()
+            },
+            &["marbles"],
+        );
+        let marble_state = engine.marble_states.get_mut("marbles").unwrap();
+        let start: DateTime<Utc> = "2020-07-12T16:32:00Z".parse().unwrap();
+        let mut decide = |second: i64, sender: &str| {
+            let mut message = chat_message("marbles", sender, "!play");
+            message.timestamp = start + chrono::Duration::seconds(second);
+            marble_state.process_message(&message)
+        };
+
+        assert_eq!(decide(0, "a"), Decision::BelowThreshold);
+        assert_eq!(decide(6, "b"), Decision::BelowThreshold);
+        assert_eq!(decide(12, "c"), Decision::BelowThreshold);
+
+        assert_eq!(decide(100, "d"), Decision::BelowThreshold);
+        assert_eq!(decide(105, "e"), Decision::BelowThreshold);
+        assert!(matches!(decide(110, "f"), Decision::Play(_)));
+    }
+
+    #[test]
+    fn ignored_users_and_own_accounts_are_not_buffered() {
+        let mut channel_factory = ChannelFactory::for_test(ChannelConfig {
+            ignore_own: Some(true),
+            ignored_users: Some(vec!["SpamBot".to_owned()]),
+            ..ChannelConfig::default()
+        });
+        channel_factory
+            .config
+            .account
+            .insert("alice".to_owned(), config::AccountConfig::default());
+        let mut marble_state = channel_factory.create("marbles".to_owned()).unwrap();
+
+        for sender in ["spambot", "justinfan12345", "alice"] {
+            assert_eq!(
+                marble_state.process_message(&chat_message("marbles", sender, "!play")),
+                Decision::Ignored
+            );
+        }
+        assert_eq!(buffered_plays(&marble_state), 0);
+        for sender in ["a", "b", "c", "d"] {
+            marble_state.process_message(&chat_message("marbles", sender, "!play"));
+        }
+        for _ in 0..20 {
+            marble_state.process_message(&chat_message("marbles", "spambot", "!play"));
+        }
+        assert_eq!(buffered_plays(&marble_state), 4);
+        assert!(marble_state
+            .process_message(&chat_message("marbles", "e", "!play"))
+            .into_play()
+            .is_some());
+    }
+
+    #[test]
+    fn answers_each_trigger_pattern_with_its_response() {
+        let (mut engine, _) = engine_with(
+            ChannelConfig {
+                treshhold: Some(2),
+                delay: Some(0),
+                wait: Some(0),
+                play_message: Some("!play :)".to_owned()),
+                triggers: Some(vec![
+                    config::TriggerConfig {
+                        pattern: "^!race".to_owned(),
+                        response: Some("!race".to_owned()),
+                    },
+                    config::TriggerConfig {
+                        pattern: "^!play".to_owned(),
+                        response: None,
+                    },
+                ]),
+                ..ChannelConfig::default()
+            },
+            &["marbles"],
+        );
+        let marble_state = engine.marble_states.get_mut("marbles").unwrap();
+
+        assert_eq!(send(marble_state, "a", "!join"), None);
+        assert_eq!(send(marble_state, "b", "!RACE"), None);
+        assert_eq!(send(marble_state, "c", "!PLAY"), None);
+        assert_eq!(
+            send(marble_state, "d", "!race please").unwrap().response,
+            "!race"
+        );
+        assert_eq!(send(marble_state, "e", "!play"), None);
+        assert_eq!(
+            send(marble_state, "f", "!Play").unwrap().response,
+            "!play :)"
+        );
+    }
+
+    #[test]
+    fn refuses_to_play_once_the_announced_lobby_closes() {
+        let (mut engine, _) = engine_with(
+            ChannelConfig {
+                announcers: Some(vec!["marblesbot".to_owned()]),
+                trigger_policy: Some(TriggerPolicy::Both),
+                lobby_duration: Some(60),
+                ..ChannelConfig::default()
+            },
+            &["marbles"],
+        );
+        let marble_state = engine.marble_states.get_mut("marbles").unwrap();
+        let clock = marble_state.clock.clone();
+        let decide = |marble_state: &mut ChannelMarbleState, sender: &str, text: &str| {
+            marble_state.process_message(&chat_message("marbles", sender, text))
+        };
+
+        assert_eq!(
+            decide(marble_state, "marblesbot", "Type !play to join"),
+            Decision::BelowThreshold
+        );
+        clock.advance(Duration::from_secs(56));
+        for sender in ["a", "b", "c", "d"] {
+            decide(marble_state, sender, "!play");
+        }
+        assert_eq!(decide(marble_state, "e", "!play"), Decision::LobbyClosed);
+        assert_eq!(marble_state.metrics.plays_late.get(), 1);
+        assert!(marble_state.is_time_to_play());
+        assert!(marble_state.status("joined".to_owned()).lobby_open);
+    }
+
+    #[tokio::test]
+    async fn runs_on_an_in_memory_chat() {
+        let (chat, source) = MemoryChat::new();
+        let channel_factory = ChannelFactory::for_test(ChannelConfig {
+            wait: Some(0),
+            ..ChannelConfig::default()
+        });
+        let mut engine = Engine::new(channel_factory, chat.clone());
+        engine.add_channel("marbles".to_owned()).unwrap();
+        let (_requests, incoming_requests) = mpsc::unbounded_channel();
+        tokio::spawn(engine.run(source, incoming_requests));
+
+        for sender in ["a", "b", "c", "d", "e"] {
+            chat.push(message("marbles", sender, "!play"));
+        }
+        let played = async {
+            while chat.said().is_empty() || chat.joined().is_empty() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), played)
+            .await
+            .expect("engine did not join and play");
+
+        assert_eq!(
+            chat.said(),
+            [("marbles".to_owned(), "!play >:(".to_owned())]
+        );
+        assert_eq!(chat.joined(), ["marbles"]);
+    }
+
+    #[tokio::test]
+    async fn dry_run_reaches_the_threshold_without_saying_anything() {
+        let (mut engine, chat) = engine_with(
+            ChannelConfig {
+                wait: Some(0),
+                dry_run: Some(true),
+                ..ChannelConfig::default()
+            },
+            &["marbles"],
+        );
+
+        for sender in ["a", "b", "c", "d", "e"] {
+            engine.handle_event(&message("marbles", sender, "!play"));
+        }
+        let pending_play = engine
+            .marble_states
+            .get_mut("marbles")
+            .unwrap()
+            .pending_play
+            .take()
+            .unwrap();
+        pending_play.await.unwrap();
+
+        let marble_state = &engine.marble_states["marbles"];
+        assert_eq!(marble_state.metrics.threshold_hits.get(), 1);
+        assert_eq!(marble_state.metrics.plays_sent.get(), 0);
+        assert!(chat.said().is_empty());
+    }
+
+    #[tokio::test]
+    async fn measures_reaction_latency_on_the_channel_clock() {
+        let (mut engine, chat) = engine_with(
+            ChannelConfig {
+                wait: Some(0),
+                ..ChannelConfig::default()
+            },
+            &["marbles"],
+        );
+        let clock = engine.channel_factory.clock.clone();
+
+        engine.handle_event(&message("marbles", "a", "!play"));
+        clock.advance(Duration::from_secs(3));
+        for sender in ["b", "c", "d", "e"] {
+            engine.handle_event(&message("marbles", sender, "!play"));
+        }
+        let marble_state = engine.marble_states.get_mut("marbles").unwrap();
+        marble_state.pending_play.take().unwrap().await.unwrap();
+
+        assert_eq!(chat.said().len(), 1);
+        let reaction_latency = &marble_state.metrics.reaction_latency;
+        assert_eq!(reaction_latency.get_sample_count(), 1);
+        assert_eq!(reaction_latency.get_sample_sum(), 3.0);
+    }
+
+    #[test]
+    fn accounts_join_and_part_channels_at_runtime() {
+        let mut engine = engine(&["marbles"]);
+        let (alice, _) = MemoryChat::new();
+        let (bob, _) = MemoryChat::new();
+        for (login, sink, channels) in [
+            ("alice", alice.clone(), Vec::new()),
+            ("bob", bob.clone(), vec!["pixelbypixel".to_owned()]),
+        ] {
+            engine.add_account(Account {
+                login: login.to_owned(),
+                sink,
+                channels,
+                delay: Duration::ZERO,
+                send_offset: Duration::ZERO,
+                limiter: SendLimiter::default(),
+            });
+        }
+
+        engine.handle_command(Command::Join("pixelbypixel".to_owned()));
+        engine.handle_command(Command::Join("cheese".to_owned()));
+        assert_eq!(alice.joined(), ["cheese", "pixelbypixel"]);
+        assert_eq!(bob.joined(), ["pixelbypixel"]);
+
+        engine.handle_command(Command::Part("pixelbypixel".to_owned()));
+        assert_eq!(alice.joined(), ["cheese"]);
+        assert!(bob.joined().is_empty());
+    }
+
+    async fn said(chat: &MemoryChat, count: usize) -> Vec<(String, String)> {
+        let played = async {
+            while chat.said().len() < count {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), played)
+            .await
+            .expect("account did not play");
+        chat.said()
+    }
+
+    #[tokio::test]
+    async fn plays_as_every_account_with_its_own_cooldown() {
+        let (mut engine, watcher) = engine_with(
+            ChannelConfig {
+                wait: Some(0),
+                ..ChannelConfig::default()
+            },
+            &["marbles", "pixelbypixel"],
+        );
+        let (alice, _) = MemoryChat::new();
+        let (bob, _) = MemoryChat::new();
+        engine.add_account(Account {
+            login: "alice".to_owned(),
+            sink: alice.clone(),
+            channels: Vec::new(),
+            delay: Duration::ZERO,
+            send_offset: Duration::ZERO,
+            limiter: SendLimiter::default(),
+        });
+        engine.add_account(Account {
+            login: "bob".to_owned(),
+            sink: bob.clone(),
+            channels: vec!["marbles".to_owned()],
+            delay: Duration::from_secs(300),
+            send_offset: Duration::from_millis(50),
+            limiter: SendLimiter::default(),
+        });
+        let clock = engine.channel_factory.clock.clone();
+        let race = |engine: &mut Engine<MemoryChat>, channel: &str| {
+            for sender in ["a", "b", "c", "d", "e"] {
+                engine.handle_event(&message(channel, sender, "!play"));
+            }
+        };
+
+        race(&mut engine, "marbles");
+        race(&mut engine, "pixelbypixel");
+        assert_eq!(said(&alice, 2).await.len(), 2);
+        assert_eq!(
+            said(&bob, 1).await,
+            [("marbles".to_owned(), "!play >:(".to_owned())]
+        );
+
+        clock.advance(Duration::from_secs(120));
+        race(&mut engine, "marbles");
+        assert_eq!(said(&alice, 3).await.len(), 3);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(bob.said().len(), 1);
+        assert!(watcher.said().is_empty());
+    }
+
+    fn account(
+        login: &str,
+        sink: &MemoryChat,
+        delay: u64,
+        send_offset: u64,
+    ) -> Account<MemoryChat> {
+        Account {
+            login: login.to_owned(),
+            sink: sink.clone(),
+            channels: Vec::new(),
+            delay: Duration::from_secs(delay),
+            send_offset: Duration::from_millis(send_offset),
+            limiter: SendLimiter::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn an_account_held_back_by_slow_mode_does_not_hold_back_the_others() {
+        let (mut engine, _) = engine_with(
+            ChannelConfig {
+                wait: Some(0),
+                ..ChannelConfig::default()
+            },
+            &["marbles"],
+        );
+        let (alice, _) = MemoryChat::new();
+        let (bob, _) = MemoryChat::new();
+        let alice_account = account("alice", &alice, 0, 0);
+        let clock = engine.channel_factory.clock.clone();
+        let room = RoomState {
+            slow_mode: Duration::from_secs(1),
+            ..RoomState::default()
+        };
+        alice_account
+            .limiter
+            .acquire("marbles", &room, clock.now())
+            .unwrap();
+        engine.add_account(alice_account);
+        engine.add_account(account("bob", &bob, 0, 50));
+        engine.handle_event(&ChatEvent::RoomState(
+            "marbles".to_owned(),
+            limits::RoomStateUpdate {
+                slow_mode: Some(room.slow_mode),
+                ..limits::RoomStateUpdate::default()
+            },
+        ));
+
+        for sender in ["a", "b", "c", "d", "e"] {
+            engine.handle_event(&message("marbles", sender, "!play"));
+        }
+        assert_eq!(said(&bob, 1).await.len(), 1);
+        assert!(alice.said().is_empty());
+
+        clock.advance(room.slow_mode);
+        assert_eq!(said(&alice, 1).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_chat_mode_rejecting_a_play_starts_no_cooldown() {
+        let (mut engine, _) = engine_with(
+            ChannelConfig {
+                wait: Some(0),
+                ..ChannelConfig::default()
+            },
+            &["marbles"],
+        );
+        let (alice, _) = MemoryChat::new();
+        let (bob, _) = MemoryChat::new();
+        engine.add_account(account("alice", &alice, 0, 0));
+        engine.add_account(account("bob", &bob, 300, 0));
+        let clock = engine.channel_factory.clock.clone();
+        let emote_only = |engine: &mut Engine<MemoryChat>, emote_only: bool| {
+            engine.handle_event(&ChatEvent::RoomState(
+                "marbles".to_owned(),
+                limits::RoomStateUpdate {
+                    emote_only: Some(emote_only),
+                    ..limits::RoomStateUpdate::default()
+                },
+            ));
+        };
+        let race = |engine: &mut Engine<MemoryChat>| {
+            for sender in ["a", "b", "c", "d", "e"] {
+                engine.handle_event(&message("marbles", sender, "!play"));
+            }
+        };
+
+        // nobody can play, so the channel doesn't cool down
+        emote_only(&mut engine, true);
+        race(&mut engine);
+        let marble_state = &engine.marble_states["marbles"];
+        assert!(marble_state.is_time_to_play());
+        assert_eq!(marble_state.metrics.plays_withheld.get(), 2);
+
+        // only bob is rejected, so only alice starts her cooldown
+        engine.accounts[0]
+            .account
+            .limiter
+            .on_event(&ChatEvent::UserState(
+                "marbles".to_owned(),
+                limits::UserState {
+                    privileged: true,
+                    subscriber: false,
+                },
+            ));
+        race(&mut engine);
+        assert_eq!(said(&alice, 1).await.len(), 1);
+        assert!(bob.said().is_empty());
+
+        emote_only(&mut engine, false);
+        clock.advance(Duration::from_secs(120));
+        race(&mut engine);
+        assert_eq!(said(&alice, 2).await.len(), 2);
+        assert_eq!(said(&bob, 1).await.len(), 1);
+    }
+}
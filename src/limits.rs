@@ -0,0 +1,234 @@
+//! What Twitch lets a play through: the chat modes of a channel from its `ROOMSTATE`, the badges
+//! of an account in it from its `USERSTATE`, and how many messages an account may send within 30
+//! seconds across all channels.
+
+use crate::chat::ChatEvent;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The window Twitch's message rate limits apply to.
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(30);
+/// Messages an account may send within the window.
+const RATE_LIMIT: usize = 20;
+/// Messages within the window for the broadcaster, a moderator or a VIP of the channel sent to.
+const PRIVILEGED_RATE_LIMIT: usize = 100;
+
+/// The chat modes of a channel.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoomState {
+    /// Least time between two messages of a user, zero if slow mode is off.
+    pub slow_mode: Duration,
+    /// How long users have to follow to chat, `None` if followers-only mode is off.
+    pub followers_only: Option<Duration>,
+    pub subscribers_only: bool,
+    pub emote_only: bool,
+}
+
+/// A `ROOMSTATE`, which only carries the modes that changed unless it's the one after joining.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoomStateUpdate {
+    pub slow_mode: Option<Duration>,
+    pub followers_only: Option<Option<Duration>>,
+    pub subscribers_only: Option<bool>,
+    pub emote_only: Option<bool>,
+}
+
+impl RoomState {
+    pub fn apply(self: &mut RoomState, update: &RoomStateUpdate) {
+        if let Some(slow_mode) = update.slow_mode {
+            self.slow_mode = slow_mode;
+        }
+        if let Some(followers_only) = update.followers_only {
+            self.followers_only = followers_only;
+        }
+        if let Some(subscribers_only) = update.subscribers_only {
+            self.subscribers_only = subscribers_only;
+        }
+        if let Some(emote_only) = update.emote_only {
+            self.emote_only = emote_only;
+        }
+    }
+}
+
+/// The badges of a connection's own user in a channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UserState {
+    /// Broadcaster, moderator or VIP, who are exempt from the chat modes and may send more.
+    pub privileged: bool,
+    pub subscriber: bool,
+}
+
+/// Why a play isn't sent right now.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Withheld {
+    /// The channel would reject it.
+    Rejected(&'static str),
+    /// It can be sent once the given time has passed.
+    Deferred(Duration, &'static str),
+}
+
+/// Keeps the sends of one account within what Twitch accepts. Cloned between the engine, the
+/// tasks sending plays and whatever reads the account's connection.
+///
+/// Followers-only mode isn't enforced, whether the account follows long enough isn't known
+/// up front.
+#[derive(Debug, Clone, Default)]
+pub struct SendLimiter {
+    state: Arc<Mutex<LimiterState>>,
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    user_states: HashMap<String, UserState>,
+    /// When the sends within the last window happened, oldest first.
+    sent: VecDeque<Instant>,
+    /// When the last send in each channel happened, for slow mode.
+    last_sent: HashMap<String, Instant>,
+}
+
+impl SendLimiter {
+    /// Notes the account's badges from the `USERSTATE`s of its connection.
+    pub fn on_event(self: &SendLimiter, event: &ChatEvent) {
+        if let ChatEvent::UserState(channel, user_state) = event {
+            self.state
+                .lock()
+                .unwrap()
+                .user_states
+                .insert(channel.to_owned(), *user_state);
+        }
+    }
+
+    /// Why the chat modes of `channel` would reject any play of the account, if they would.
+    pub fn rejection(self: &SendLimiter, channel: &str, room: &RoomState) -> Option<&'static str> {
+        let state = self.state.lock().unwrap();
+        rejection(
+            state.user_states.get(channel).copied().unwrap_or_default(),
+            room,
+        )
+    }
+
+    /// Counts a send in `channel` at `now` if its chat modes and the rate limit let it through.
+    pub fn acquire(
+        self: &SendLimiter,
+        channel: &str,
+        room: &RoomState,
+        now: Instant,
+    ) -> Result<(), Withheld> {
+        let mut state = self.state.lock().unwrap();
+        let user_state = state.user_states.get(channel).copied().unwrap_or_default();
+        if let Some(reason) = rejection(user_state, room) {
+            return Err(Withheld::Rejected(reason));
+        }
+        if !user_state.privileged {
+            if let Some(last_sent) = state.last_sent.get(channel) {
+                let allowed_at = *last_sent + room.slow_mode;
+                if allowed_at > now {
+                    return Err(Withheld::Deferred(allowed_at - now, "slow mode"));
+                }
+            }
+        }
+        while state
+            .sent
+            .front()
+            .is_some_and(|sent| *sent + RATE_LIMIT_WINDOW <= now)
+        {
+            state.sent.pop_front();
+        }
+        let limit = if user_state.privileged {
+            PRIVILEGED_RATE_LIMIT
+        } else {
+            RATE_LIMIT
+        };
+        if state.sent.len() >= limit {
+            let frees_at = state.sent[state.sent.len() - limit] + RATE_LIMIT_WINDOW;
+            return Err(Withheld::Deferred(frees_at - now, "rate limit"));
+        }
+        state.sent.push_back(now);
+        state.last_sent.insert(channel.to_owned(), now);
+        Ok(())
+    }
+}
+
+fn rejection(user_state: UserState, room: &RoomState) -> Option<&'static str> {
+    if user_state.privileged {
+        None
+    } else if room.emote_only {
+        Some("emote-only mode")
+    } else if room.subscribers_only && !user_state.subscriber {
+        Some("subscribers-only mode")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_back_what_the_channel_would_reject() {
+        let limiter = SendLimiter::default();
+        let start = Instant::now();
+        let seconds = |seconds| start + Duration::from_secs(seconds);
+        let mut room = RoomState {
+            slow_mode: Duration::from_secs(10),
+            ..RoomState::default()
+        };
+
+        assert_eq!(limiter.acquire("marbles", &room, start), Ok(()));
+        assert_eq!(
+            limiter.acquire("marbles", &room, seconds(4)),
+            Err(Withheld::Deferred(Duration::from_secs(6), "slow mode"))
+        );
+        assert_eq!(limiter.acquire("pixelbypixel", &room, seconds(4)), Ok(()));
+
+        room.apply(&RoomStateUpdate {
+            subscribers_only: Some(true),
+            ..RoomStateUpdate::default()
+        });
+        assert_eq!(room.slow_mode, Duration::from_secs(10));
+        assert_eq!(
+            limiter.acquire("marbles", &room, seconds(10)),
+            Err(Withheld::Rejected("subscribers-only mode"))
+        );
+        assert_eq!(
+            limiter.rejection("marbles", &room),
+            Some("subscribers-only mode")
+        );
+        limiter.on_event(&ChatEvent::UserState(
+            "marbles".to_owned(),
+            UserState {
+                privileged: true,
+                subscriber: false,
+            },
+        ));
+        assert_eq!(limiter.rejection("marbles", &room), None);
+        assert_eq!(limiter.acquire("marbles", &room, seconds(5)), Ok(()));
+    }
+
+    #[test]
+    fn keeps_to_the_rate_limit_across_channels() {
+        let limiter = SendLimiter::default();
+        let start = Instant::now();
+        let room = RoomState::default();
+        for second in 0..20 {
+            let channel = if second % 2 == 0 {
+                "marbles"
+            } else {
+                "pixelbypixel"
+            };
+            let now = start + Duration::from_secs(second);
+            assert_eq!(limiter.acquire(channel, &room, now), Ok(()));
+        }
+
+        assert_eq!(
+            limiter.acquire("marbles", &room, start + Duration::from_secs(20)),
+            Err(Withheld::Deferred(Duration::from_secs(10), "rate limit"))
+        );
+        assert_eq!(
+            limiter.acquire("marbles", &room, start + Duration::from_secs(30)),
+            Ok(())
+        );
+    }
+}
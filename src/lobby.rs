@@ -0,0 +1,85 @@
+use crate::config::ChannelParams;
+use std::collections::VecDeque;
+use std::ops::Add;
+use std::time::{Duration, Instant};
+
+/// An estimate of whether a channel's race lobby still takes players. A lobby opens when the
+/// trigger fires, or when the race was announced, and closes `lobby_duration` later or as soon as
+/// trigger matches die down below `lobby_min_matches` per `window`, whichever comes first.
+#[derive(Debug, Default)]
+pub struct Lobby {
+    opened_at: Option<Instant>,
+    /// When the recent trigger matches came in, oldest first.
+    matches: VecDeque<Instant>,
+}
+
+impl Lobby {
+    pub fn on_match(self: &mut Lobby, now: Instant, window: Duration) {
+        self.matches.push_back(now);
+        while let Some(matched_at) = self.matches.front() {
+            if matched_at.add(window) >= now {
+                break;
+            }
+            self.matches.pop_front();
+        }
+    }
+
+    pub fn open(self: &mut Lobby, opened_at: Instant) {
+        self.opened_at = Some(opened_at);
+    }
+
+    /// When the lobby closes at the latest, regardless of how busy chat is.
+    pub fn closes_at(self: &Lobby, params: &ChannelParams) -> Option<Instant> {
+        self.opened_at
+            .map(|opened_at| opened_at.add(params.lobby_duration))
+    }
+
+    pub fn is_open(self: &Lobby, now: Instant, params: &ChannelParams) -> bool {
+        let recent_matches = self
+            .matches
+            .iter()
+            .filter(|matched_at| matched_at.add(params.window) >= now)
+            .count();
+        self.closes_at(params)
+            .is_some_and(|closes_at| closes_at > now)
+            && recent_matches >= params.lobby_min_matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ChannelConfig;
+    use crate::ChannelFactory;
+
+    fn params(lobby_min_matches: usize) -> ChannelParams {
+        ChannelFactory::for_test(ChannelConfig {
+            window: Some(10),
+            lobby_duration: Some(60),
+            lobby_min_matches: Some(lobby_min_matches),
+            ..ChannelConfig::default()
+        })
+        .resolve("marbles")
+        .unwrap()
+    }
+
+    #[test]
+    fn closes_after_its_duration_or_once_matches_die_down() {
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let mut lobby = Lobby::default();
+        assert!(!lobby.is_open(start, &params(0)));
+
+        for second in 0..3 {
+            lobby.on_match(at(second), Duration::from_secs(10));
+        }
+        lobby.open(at(2));
+
+        assert!(lobby.is_open(at(12), &params(0)));
+        assert!(lobby.is_open(at(12), &params(1)));
+        assert!(!lobby.is_open(at(13), &params(1)));
+        assert!(lobby.is_open(at(61), &params(0)));
+        assert!(!lobby.is_open(at(62), &params(0)));
+        assert_eq!(lobby.closes_at(&params(0)), Some(at(62)));
+    }
+}
@@ -1,13 +1,33 @@
+mod credentials;
+mod logging;
+mod token_storage;
+
 use clap::Parser;
-use std::collections::HashMap;
-use std::iter;
-use std::ops::Add;
-use std::thread;
-use std::time::{Duration, Instant};
+use credentials::Credentials;
+use logging::{LogFormat, LogLevel};
+use marblejoiner::chat::{ChatEvent, ChatMessage};
+use marblejoiner::clock::Clock;
+use marblejoiner::config::{
+    normalize_channel, ChannelConfig, Config, TriggerConfig, TriggerMode, TriggerPolicy,
+    DEFAULT_SEND_STAGGER,
+};
+use marblejoiner::control::{self, ControlRequest};
+use marblejoiner::limits::SendLimiter;
+use marblejoiner::metrics::Metrics;
+use marblejoiner::recorder::Recorder;
+use marblejoiner::transport::{self, Endpoint, Server, TransportKind};
+use marblejoiner::{http, replay, tune, Account, ChannelFactory, Engine};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+use token_storage::JsonFileTokenStorage;
+use tokio::sync::mpsc;
+use tracing::{error, info};
+use twitch_irc::transport::{tcp::TCPTransport, websocket::WSTransport, Transport};
 use twitch_irc::{
-    login::{CredentialsPair, StaticLoginCredentials},
-    message::ServerMessage,
-    ClientConfig, SecureTCPTransport, TwitchIRCClient,
+    login::StaticLoginCredentials, ClientConfig, PlainTCPTransport, PlainWSTransport,
+    SecureTCPTransport, SecureWSTransport, TwitchIRCClient,
 };
 
 #[derive(Parser, Default, Debug)]
@@ -15,59 +35,245 @@ use twitch_irc::{
     author = "shearqan",
     about = "An app that joins marbles on stream races for you"
 )]
+#[clap(subcommand_negates_reqs = true)]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Subcommand>,
+
     #[clap(
         short,
         long,
-        default_value_t = 10,
         value_parser,
-        help = "How many last messages are considered"
+        help = "Path to a TOML config file with global settings and [channel.<name>] overrides, command line flags take precedence over it"
     )]
-    buffer_size: usize,
+    config: Option<PathBuf>,
 
     #[clap(
         short,
         long,
-        default_value_t = 5,
         value_parser,
-        help = "How many of the buffered messages have to start with !play in order to trigger"
+        help = "How many last messages are considered [default: 10]"
     )]
-    treshhold: usize,
+    buffer_size: Option<usize>,
+
     #[clap(
         short,
         long,
-        default_value_t = 120,
         value_parser,
-        help = "Delay in seconds on minimum time between two plays from this app"
+        help = "How many distinct users have to send a message matching a trigger pattern within the buffered messages in order to trigger [default: 5]"
     )]
-    delay: u64,
+    treshhold: Option<usize>,
+    #[clap(
+        short,
+        long,
+        value_parser,
+        help = "Delay in seconds on minimum time between two plays from this app [default: 120]"
+    )]
+    delay: Option<u64>,
 
     #[clap(
         short,
         long,
-        default_value_t = 5,
         value_parser,
-        help = "Time to wait in seconds before posting the !play due to the idiotic combination of the game not letting you join before the cutscene on some map starts and fucking idiots joining during the loading screen already"
+        help = "Time to wait in seconds before posting the !play due to the idiotic combination of the game not letting you join before the cutscene on some map starts and fucking idiots joining during the loading screen already [default: 5]"
     )]
-    wait: u64,
+    wait: Option<u64>,
 
     #[clap(
         short,
         long,
-        default_value = "!play >:(",
         value_parser,
-        help = "The message the app joins the race for you with"
+        help = "The message the app joins the race for you with [default: \"!play >:(\"]"
     )]
-    play_message: String,
+    play_message: Option<String>,
 
-    #[clap(forbid_empty_values = true, help = "Your twitch login name")]
-    login: String,
+    #[clap(
+        long = "trigger",
+        value_parser,
+        help = "Case-insensitive regex of chat messages that count towards the threshold and are answered with the play message, can be given multiple times, per-pattern responses can be set in the config file [default: \"^!play\"]"
+    )]
+    triggers: Vec<String>,
+
+    #[clap(
+        long = "announcer",
+        value_parser,
+        help = "Login of a streamer or bot whose race announcements trigger a join, can be given multiple times"
+    )]
+    announcers: Vec<String>,
+
+    #[clap(
+        long = "announcement-pattern",
+        value_parser,
+        help = "Regex an announcer message has to match to count as a race announcement, can be given multiple times [default: \"(?i)!play\"]"
+    )]
+    announcement_patterns: Vec<String>,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "Whether the !play threshold and a race announcement each trigger a join on their own or are both required [default: either]"
+    )]
+    trigger_policy: Option<TriggerPolicy>,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "Whether the threshold considers the last buffered messages or all messages within the last window seconds [default: buffer]"
+    )]
+    trigger_mode: Option<TriggerMode>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Length in seconds of the window considered in the window trigger mode [default: 30]"
+    )]
+    window: Option<u64>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Seconds a race lobby stays open after the trigger fired or the race was announced, plays that would be sent later are dropped [default: 120]"
+    )]
+    lobby_duration: Option<u64>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Fewest trigger matches within the last window seconds that keep a lobby open, a play due after chat died down below that is dropped [default: 0]"
+    )]
+    lobby_min_matches: Option<usize>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Instead of waiting --wait seconds, send plays at this percentile (0 to 100) of how long after the first !play of a race chatters joined in past races, once a few races were seen"
+    )]
+    wait_percentile: Option<f64>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Seconds a wait learned through --wait-percentile is randomly moved earlier or later [default: 0]"
+    )]
+    wait_jitter: Option<f64>,
+
+    #[clap(
+        long,
+        action,
+        help = "Don't count your own messages towards the threshold"
+    )]
+    ignore_own: bool,
+
+    #[clap(
+        long = "ignore",
+        value_parser,
+        help = "Login of a user whose messages never count towards the threshold, e.g. a spam bot, can be given multiple times"
+    )]
+    ignored_users: Vec<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "File to keep updated with the connection health, \"ok\" or \"degraded\" followed by the state of every channel"
+    )]
+    health_file: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "Most verbose level to log at, overridden by RUST_LOG"
+    )]
+    log_level: LogLevel,
+
+    #[clap(long, value_enum, default_value_t, help = "How to format log output")]
+    log_format: LogFormat,
+
+    #[clap(
+        long,
+        value_parser,
+        conflicts_with = "oauth-stdin",
+        help = "File containing your oauth token, must not be readable by everyone"
+    )]
+    oauth_file: Option<PathBuf>,
+
+    #[clap(
+        long,
+        action,
+        help = "Read your oauth token from stdin, used if neither --oauth-file nor the MARBLEJOINER_OAUTH environment variable are given"
+    )]
+    oauth_stdin: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        conflicts_with_all = &["oauth-file", "oauth-stdin", "oauth"],
+        help = "JSON file with client_id, client_secret, access_token and refresh_token of a Twitch application, the token is refreshed and written back whenever it expires"
+    )]
+    token_file: Option<PathBuf>,
+
+    #[clap(
+        long,
+        action,
+        help = "Read commands from stdin while running: join <channel>, part <channel>, pause [channel], resume [channel], status and set <channel> <setting> <value>"
+    )]
+    console: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Address to serve a JSON status and control API on, e.g. 127.0.0.1:8080, has no authentication so keep it on localhost"
+    )]
+    http: Option<SocketAddr>,
+
+    #[clap(
+        long,
+        action,
+        help = "Run the full detection but only log the plays that would have been sent"
+    )]
+    dry_run: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Directory to record every chat message to, together with whether and why it triggered a play, one JSONL file per channel and day that can be replayed later"
+    )]
+    record: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "How to connect to the chat server: IRC over plain or TLS secured TCP, or over plain or TLS secured WebSocket"
+    )]
+    transport: TransportKind,
+
+    #[clap(
+        long,
+        value_parser = transport::parse_server,
+        help = "host:port of the chat server to connect to instead of Twitch's, e.g. a local stand-in for testing"
+    )]
+    server: Option<Server>,
+
+    #[clap(
+        long,
+        action,
+        conflicts_with_all = &["oauth-file", "oauth-stdin", "token-file", "oauth"],
+        help = "Watch chat without logging in, needs neither a login nor an oauth token and implies --dry-run"
+    )]
+    anonymous: bool,
+
+    #[clap(
+        forbid_empty_values = true,
+        help = "Your twitch login name, required unless watching --anonymous or playing only as the accounts in the config file"
+    )]
+    login: Option<String>,
 
     #[clap(
         forbid_empty_values = true,
-        help = "Your oauth authorization token according to https://dev.twitch.tv/docs/authentication/getting-tokens-oauth, if you have no idea what this means, use this site to get one: https://twitchapps.com/tmi/"
+        help = "Deprecated, leaks into your shell history, use --oauth-file, --oauth-stdin or the MARBLEJOINER_OAUTH environment variable instead. Your oauth authorization token according to https://dev.twitch.tv/docs/authentication/getting-tokens-oauth, if you have no idea what this means, use this site to get one: https://twitchapps.com/tmi/"
     )]
-    oauth: String,
+    oauth: Option<String>,
 
     #[clap(
         last = true,
@@ -79,148 +285,518 @@ struct Cli {
     channels: Vec<String>,
 }
 
-#[derive(Debug)]
-struct AppParams {
-    buffer_size: usize,
-    treshhold: usize,
-    delay: Duration,
-    wait: Duration,
-    play_message: String,
-    login: String,
-    oauth: String,
-}
+#[derive(clap::Subcommand, Debug)]
+enum Subcommand {
+    /// Replay recorded chat through the trigger logic with the given settings and print when it
+    /// would have played. Logs hold raw IRC lines or JSON lines with timestamp, channel, sender
+    /// and text.
+    Replay {
+        #[clap(value_parser, required = true, help = "Chat logs to replay, in order")]
+        logs: Vec<PathBuf>,
+    },
+    /// Replay recorded chat with every combination of the given buffer sizes, windows,
+    /// treshholds and trigger modes, and print how precisely each one joined the races that
+    /// actually happened and how many of them it caught.
+    Tune {
+        #[clap(
+            long,
+            value_parser,
+            help = "JSON lines with channel, start and end of every race in the logs"
+        )]
+        races: PathBuf,
 
-#[derive(Debug)]
-struct ChannelMarbleState {
-    login: String,
-    buffer: Vec<bool>,
-    current_position: usize,
-    next_play: Instant,
+        #[clap(
+            long,
+            value_parser,
+            value_delimiter = ',',
+            default_value = "5,10,15,20,30",
+            help = "Buffer sizes to try in the buffer trigger mode"
+        )]
+        buffer_sizes: Vec<usize>,
+
+        #[clap(
+            long,
+            value_parser,
+            value_delimiter = ',',
+            default_value = "15,30,60",
+            help = "Windows in seconds to try in the window trigger mode"
+        )]
+        windows: Vec<u64>,
+
+        #[clap(
+            long,
+            value_parser,
+            value_delimiter = ',',
+            default_value = "2,3,4,5,6,7,8,10",
+            help = "Treshholds to try"
+        )]
+        treshholds: Vec<usize>,
+
+        #[clap(
+            long,
+            value_enum,
+            value_delimiter = ',',
+            default_value = "buffer,window",
+            help = "Trigger modes to try"
+        )]
+        trigger_modes: Vec<TriggerMode>,
+
+        #[clap(value_parser, required = true, help = "Chat logs to replay, in order")]
+        logs: Vec<PathBuf>,
+    },
 }
 
 #[tokio::main]
 async fn main() {
     let args = Cli::parse();
-    let mut marble_states: HashMap<String, ChannelMarbleState> = args
-        .channels
-        .into_iter()
-        .map(|channel| {
-            (
-                channel.to_owned(),
-                ChannelMarbleState::new(channel, args.buffer_size),
-            )
-        })
-        .collect();
-    let app_params = AppParams {
-        buffer_size: args.buffer_size,
-        treshhold: args.treshhold,
-        delay: Duration::from_secs(args.delay),
-        wait: Duration::from_secs(args.wait),
-        play_message: args.play_message,
-        login: args.login,
-        oauth: args.oauth.replacen("oauth:", "", 1),
-    };
-
-    let credentials = StaticLoginCredentials {
-        credentials: CredentialsPair {
-            login: app_params.login.to_owned(),
-            token: Some(app_params.oauth.to_owned()),
+    logging::init(args.log_level, args.log_format);
+    let config = match &args.config {
+        Some(path) => match Config::load(path) {
+            Ok(config) => config,
+            Err(error) => {
+                error!(path = %path.display(), "{}", error);
+                std::process::exit(1);
+            }
         },
+        None => Config::default(),
     };
-    let config = ClientConfig {
-        login_credentials: credentials,
-        ..ClientConfig::default()
+    let overrides = overrides(&args);
+    // without a login of its own, the app watches anonymously and plays as the accounts only
+    let watch_anonymously = args.anonymous || args.login.is_none();
+    let login = match args.login {
+        Some(login) => login,
+        None => StaticLoginCredentials::anonymous().credentials.login,
     };
-    let (mut incoming_messages, client) =
-        TwitchIRCClient::<SecureTCPTransport, StaticLoginCredentials>::new(config);
-    let client = Box::new(client);
 
-    let join_handle = tokio::spawn(async move {
-        for channel in marble_states.keys() {
-            client.join(channel.to_string()).unwrap();
+    let mut channels: Vec<String> = Vec::new();
+    for channel in args
+        .channels
+        .iter()
+        .map(|channel| normalize_channel(channel))
+        .chain(config.channel.keys().cloned())
+        .chain(
+            config
+                .account
+                .values()
+                .flat_map(|account| account.channels.iter().cloned()),
+        )
+    {
+        if !channel.is_empty() && !channels.contains(&channel) {
+            channels.push(channel);
         }
-
-        while let Some(message) = incoming_messages.recv().await {
-            println!("Received message: {:?}", message);
-            println!();
-            let _ = process_message(&app_params, &mut marble_states, &message, &client).await;
+    }
+    if let Some(Subcommand::Tune {
+        races,
+        buffer_sizes,
+        windows,
+        treshholds,
+        trigger_modes,
+        logs,
+    }) = &args.command
+    {
+        let grid = tune::Grid {
+            trigger_modes: trigger_modes.to_owned(),
+            buffer_sizes: buffer_sizes.to_owned(),
+            windows: windows.to_owned(),
+            treshholds: treshholds.to_owned(),
+        };
+        run_tune(logs, races, &config, &overrides, &grid);
+        return;
+    }
+    if let Some(Subcommand::Replay { logs }) = &args.command {
+        let channel_factory = ChannelFactory {
+            config,
+            overrides,
+            login,
+            metrics: Metrics::new(),
+            clock: Clock::new_virtual(),
+        };
+        run_replay(logs, &channel_factory);
+        return;
+    }
+    if watch_anonymously && !args.anonymous && config.account.is_empty() {
+        error!("no login given, pass your login, --anonymous or accounts in the config file");
+        std::process::exit(1);
+    }
+    let accounts = account_logins(&config, (!watch_anonymously).then_some(login.as_str()));
+    let channel_factory = ChannelFactory {
+        config,
+        overrides,
+        login: login.to_owned(),
+        metrics: Metrics::new(),
+        clock: Clock::System,
+    };
+    // before asking for credentials, so a broken config doesn't wait on stdin first
+    for channel in channels.iter() {
+        if let Err(error) = channel_factory.resolve(channel) {
+            error!(channel = %channel, "{}", error);
+            std::process::exit(1);
+        }
+    }
+    let credentials = if watch_anonymously {
+        Credentials::Static(StaticLoginCredentials::anonymous())
+    } else if let Some(path) = &args.token_file {
+        match JsonFileTokenStorage::open(path) {
+            Ok(token_storage) => Credentials::refreshing(login, token_storage),
+            Err(error) => {
+                error!("{}", error);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        let oauth =
+            match credentials::read_oauth(args.oauth, args.oauth_file.as_deref(), args.oauth_stdin)
+            {
+                Ok(oauth) => oauth,
+                Err(error) => {
+                    error!("{}", error);
+                    std::process::exit(1);
+                }
+            };
+        Credentials::Static(StaticLoginCredentials::new(
+            login,
+            Some(oauth.replacen("oauth:", "", 1)),
+        ))
+    };
+    let client_config = ClientConfig::new_simple(credentials);
+    let recorder = args.record.map(|dir| match Recorder::new(dir.to_owned()) {
+        Ok(recorder) => recorder,
+        Err(error) => {
+            error!(dir = %dir.display(), "could not create recording directory: {}", error);
+            std::process::exit(1);
         }
     });
 
-    join_handle.await.unwrap();
+    let (control_requests, incoming_requests) = mpsc::unbounded_channel::<ControlRequest>();
+    if args.console {
+        tokio::spawn(control::run_console(control_requests.clone()));
+    }
+    if let Some(address) = args.http {
+        let listener = match std::net::TcpListener::bind(address)
+            .and_then(|listener| listener.set_nonblocking(true).map(|()| listener))
+        {
+            Ok(listener) => listener,
+            Err(error) => {
+                error!(address = %address, "could not listen for http: {}", error);
+                std::process::exit(1);
+            }
+        };
+        info!(address = %address, "serving http api");
+        let requests = control_requests.clone();
+        let metrics = channel_factory.metrics.clone();
+        tokio::spawn(async move {
+            if let Err(error) = http::serve(listener, requests, metrics).await {
+                error!("http api failed: {}", error);
+            }
+        });
+    }
+
+    let connection = Connection {
+        client_config,
+        channel_factory,
+        channels,
+        accounts,
+        recorder,
+        health_file: args.health_file,
+        incoming_requests,
+    };
+    match args.server {
+        None => match args.transport {
+            TransportKind::Tcp => run::<PlainTCPTransport>(connection).await,
+            TransportKind::Tls => run::<SecureTCPTransport>(connection).await,
+            TransportKind::Ws => run::<PlainWSTransport>(connection).await,
+            TransportKind::Wss => run::<SecureWSTransport>(connection).await,
+        },
+        Some(server) => {
+            info!(server = %server, transport = ?args.transport, "connecting to custom server");
+            SERVER.set(server).expect("--server is only set once");
+            match args.transport {
+                TransportKind::Tcp => {
+                    run::<TCPTransport<transport::Plain<CustomServer>>>(connection).await
+                }
+                TransportKind::Tls => {
+                    run::<TCPTransport<transport::Tls<CustomServer>>>(connection).await
+                }
+                TransportKind::Ws => {
+                    run::<WSTransport<transport::Ws<CustomServer>>>(connection).await
+                }
+                TransportKind::Wss => {
+                    run::<WSTransport<transport::Wss<CustomServer>>>(connection).await
+                }
+            }
+        }
+    }
+}
+
+/// The server given with `--server`, set once before the first connection is made. twitch-irc
+/// connects without taking any arguments, so `CustomServer` can only find it in a static.
+static SERVER: OnceLock<Server> = OnceLock::new();
+
+/// Points the transports of `transport` to the server given with `--server`.
+struct CustomServer;
 
-    return;
+impl Endpoint for CustomServer {
+    fn server() -> Server {
+        SERVER
+            .get()
+            .expect("no --server set before connecting")
+            .to_owned()
+    }
 }
 
-async fn process_message(
-    app_params: &AppParams,
-    marble_states: &mut HashMap<String, ChannelMarbleState>,
-    server_message: &ServerMessage,
-    client: &TwitchIRCClient<SecureTCPTransport, StaticLoginCredentials>,
-) {
-    match server_message {
-        ServerMessage::Privmsg(message) => {
-            marble_states
-                .get_mut(&message.channel_login)
-                .unwrap()
-                .process_message(app_params, &message.message_text, client)
-                .await;
-        }
-        _ => {}
+/// Everything the engine is started with once the transport is chosen.
+struct Connection {
+    client_config: ClientConfig<Credentials>,
+    channel_factory: ChannelFactory,
+    channels: Vec<String>,
+    accounts: Vec<AccountLogin>,
+    recorder: Option<Recorder>,
+    health_file: Option<PathBuf>,
+    incoming_requests: mpsc::UnboundedReceiver<ControlRequest>,
+}
+
+/// Connects through `T` and runs the engine until the connection is closed for good.
+async fn run<T: Transport>(connection: Connection) {
+    let (incoming_messages, client) =
+        TwitchIRCClient::<T, Credentials>::new(connection.client_config);
+    let mut engine = Engine::new(connection.channel_factory, client.clone());
+    for account in connection.accounts {
+        let (sink, limiter) = match account.credentials {
+            // the login given on the command line plays through the connection it watches on
+            None => (client.clone(), engine.limiter()),
+            Some(credentials) => {
+                // joins the channels it plays in once they are added to the engine
+                let (mut incoming_messages, sink) =
+                    TwitchIRCClient::<T, Credentials>::new(ClientConfig::new_simple(credentials));
+                // chat is watched on the other connection, this one only tells about the
+                // account's own badges
+                let limiter = SendLimiter::default();
+                let account_limiter = limiter.clone();
+                tokio::spawn(async move {
+                    while let Some(message) = incoming_messages.recv().await {
+                        if let Some(event) = ChatEvent::from_twitch(message) {
+                            account_limiter.on_event(&event);
+                        }
+                    }
+                });
+                (sink, limiter)
+            }
+        };
+        engine.add_account(Account {
+            login: account.login,
+            sink,
+            channels: account.channels,
+            delay: account.delay,
+            send_offset: account.send_offset,
+            limiter,
+        });
+    }
+    for channel in connection.channels {
+        engine
+            .add_channel(channel)
+            .expect("channel settings were checked on startup");
+    }
+    if let Some(recorder) = connection.recorder {
+        engine.set_recorder(recorder);
+    }
+    if let Some(path) = connection.health_file {
+        engine.set_health_file(path);
     }
+
+    tokio::spawn(engine.run(incoming_messages, connection.incoming_requests))
+        .await
+        .unwrap();
 }
 
-impl ChannelMarbleState {
-    fn new(login: String, buffer_size: usize) -> ChannelMarbleState {
-        ChannelMarbleState {
-            login,
-            buffer: iter::repeat(false).take(buffer_size).collect(),
-            current_position: 0,
-            next_play: Instant::now(),
+/// An account to play as, before its connection is made.
+struct AccountLogin {
+    login: String,
+    /// `None` for the login given on the command line, which already has a connection.
+    credentials: Option<Credentials>,
+    channels: Vec<String>,
+    delay: Duration,
+    send_offset: Duration,
+}
+
+/// Reads the credentials of every account in the config, in the order of their logins. The login
+/// given on the command line, if any, plays first as long as there are accounts at all. Accounts
+/// without a `send_offset` send `DEFAULT_SEND_STAGGER` seconds after the one before them.
+fn account_logins(config: &Config, own_login: Option<&str>) -> Vec<AccountLogin> {
+    let mut accounts: Vec<AccountLogin> = Vec::new();
+    if config.account.is_empty() {
+        return accounts;
+    }
+    if let Some(login) = own_login.filter(|login| !config.account.contains_key(*login)) {
+        accounts.push(AccountLogin {
+            login: login.to_owned(),
+            credentials: None,
+            channels: Vec::new(),
+            delay: Duration::ZERO,
+            send_offset: Duration::ZERO,
+        });
+    }
+    let mut logins: Vec<&String> = config.account.keys().collect();
+    logins.sort();
+    for login in logins {
+        let account = &config.account[login];
+        let credentials = if let Some(path) = &account.token_file {
+            JsonFileTokenStorage::open(path)
+                .map(|token_storage| Credentials::refreshing(login.to_owned(), token_storage))
+                .map_err(|error| error.to_string())
+        } else {
+            credentials::read_oauth(None, account.oauth_file.as_deref(), false)
+                .map(|oauth| {
+                    Credentials::Static(StaticLoginCredentials::new(
+                        login.to_owned(),
+                        Some(oauth.replacen("oauth:", "", 1)),
+                    ))
+                })
+                .map_err(|error| error.to_string())
+        };
+        let credentials = match credentials {
+            Ok(credentials) => credentials,
+            Err(error) => {
+                error!(account = %login, "{}", error);
+                std::process::exit(1);
+            }
+        };
+        let send_offset = account
+            .send_offset
+            .unwrap_or(accounts.last().map_or(0.0, |previous| {
+                previous.send_offset.as_secs_f64() + DEFAULT_SEND_STAGGER
+            }));
+        accounts.push(AccountLogin {
+            login: login.to_owned(),
+            credentials: Some(credentials),
+            channels: account.channels.to_owned(),
+            delay: Duration::from_secs(account.delay.unwrap_or(0)),
+            send_offset: Duration::from_secs_f64(send_offset),
+        });
+    }
+    accounts
+}
+
+fn read_logs(logs: &[PathBuf]) -> Vec<ChatMessage> {
+    let mut messages = Vec::new();
+    for path in logs {
+        match replay::read_log(path) {
+            Ok(log) => messages.extend(log),
+            Err(error) => {
+                error!("{}", error);
+                std::process::exit(1);
+            }
         }
     }
+    messages
+}
 
-    async fn process_message(
-        self: &mut ChannelMarbleState,
-        app_params: &AppParams,
-        message: &str,
-        client: &TwitchIRCClient<SecureTCPTransport, StaticLoginCredentials>,
-    ) {
-        self.current_position = (self.current_position + 1) % app_params.buffer_size;
-        let is_play_message = message.to_lowercase().starts_with("!play");
-        self.buffer[self.current_position] = is_play_message;
-        if is_play_message && self.is_time_to_play() && self.is_treshhold_reached(app_params) {
-            self.next_play = Instant::now().add(app_params.delay);
-            self.clear_buffer();
-            thread::sleep(app_params.wait);
-            self.say_play(client, app_params).await;
+fn run_replay(logs: &[PathBuf], channel_factory: &ChannelFactory) {
+    let messages = read_logs(logs);
+    match replay::replay(&messages, channel_factory) {
+        Ok(plays) => {
+            for play in plays.iter() {
+                println!("{}", play);
+            }
+            println!(
+                "{} messages replayed, {} plays",
+                messages.len(),
+                plays.len()
+            );
+        }
+        Err(error) => {
+            error!("{}", error);
+            std::process::exit(1);
         }
     }
+}
 
-    async fn say_play(
-        self: &mut ChannelMarbleState,
-        client: &TwitchIRCClient<SecureTCPTransport, StaticLoginCredentials>,
-        app_params: &AppParams,
-    ) {
-        if let Err(error) = client
-            .say(self.login.to_owned(), app_params.play_message.to_owned())
-            .await
-        {
-            println!("could not say join, because: {}", error);
+fn run_tune(
+    logs: &[PathBuf],
+    races: &Path,
+    config: &Config,
+    overrides: &ChannelConfig,
+    grid: &tune::Grid,
+) {
+    let messages = read_logs(logs);
+    let races = match tune::read_races(races) {
+        Ok(races) => races,
+        Err(error) => {
+            error!("{}", error);
+            std::process::exit(1);
+        }
+    };
+    let scores = match tune::tune(&messages, &races, config, overrides, grid) {
+        Ok(scores) => scores,
+        Err(error) => {
+            error!("{}", error);
+            std::process::exit(1);
+        }
+    };
+    for (channel, scores) in scores.iter() {
+        println!("{}:", channel);
+        for (score, pareto_best) in scores.iter().zip(tune::pareto_front(scores)) {
+            println!("  {} {}", if pareto_best { "*" } else { " " }, score);
         }
     }
+    println!("* Pareto-best: no other settings do better on precision or recall without doing worse on the other");
+}
 
-    fn is_time_to_play(self: &ChannelMarbleState) -> bool {
-        self.next_play < Instant::now()
+/// The settings given on the command line, taking precedence over the config file. Watching
+/// anonymously can't send anything, so it implies a dry run.
+fn overrides(args: &Cli) -> ChannelConfig {
+    ChannelConfig {
+        buffer_size: args.buffer_size,
+        treshhold: args.treshhold,
+        delay: args.delay,
+        wait: args.wait,
+        play_message: args.play_message.to_owned(),
+        announcers: non_empty(&args.announcers),
+        announcement_patterns: non_empty(&args.announcement_patterns),
+        trigger_policy: args.trigger_policy,
+        trigger_mode: args.trigger_mode,
+        window: args.window,
+        ignore_own: if args.ignore_own { Some(true) } else { None },
+        ignored_users: non_empty(&args.ignored_users),
+        triggers: non_empty(&args.triggers).map(|patterns| {
+            patterns
+                .into_iter()
+                .map(|pattern| TriggerConfig {
+                    pattern,
+                    response: None,
+                })
+                .collect()
+        }),
+        dry_run: if args.dry_run || args.anonymous {
+            Some(true)
+        } else {
+            None
+        },
+        lobby_duration: args.lobby_duration,
+        lobby_min_matches: args.lobby_min_matches,
+        wait_percentile: args.wait_percentile,
+        wait_jitter: args.wait_jitter,
     }
+}
 
-    fn is_treshhold_reached(self: &ChannelMarbleState, app_params: &AppParams) -> bool {
-        self.buffer.iter().filter(|x| **x).count() >= app_params.treshhold
+/// Multiple-occurrence flags can't be told apart from absent ones by clap, treat empty as unset.
+fn non_empty(values: &[String]) -> Option<Vec<String>> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.to_vec())
     }
+}
 
-    fn clear_buffer(self: &mut ChannelMarbleState) {
-        for i in 0..self.buffer.len() {
-            self.buffer[i] = false;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anonymous_watching_implies_a_dry_run() {
+        let parse = |args: &[&str]| overrides(&Cli::parse_from(args));
+
+        assert_eq!(parse(&["marblejoiner", "--anonymous"]).dry_run, Some(true));
+        assert_eq!(parse(&["marblejoiner", "--dry-run"]).dry_run, Some(true));
+        assert_eq!(parse(&["marblejoiner", "someone"]).dry_run, None);
     }
 }
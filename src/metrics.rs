@@ -0,0 +1,167 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+/// Upper bounds in seconds of the reaction latency buckets, around the usual `wait` of a few
+/// seconds and the lobby lasting a minute or two.
+const LATENCY_BUCKETS: &[f64] = &[
+    1.0, 2.5, 5.0, 7.5, 10.0, 15.0, 20.0, 30.0, 45.0, 60.0, 120.0,
+];
+
+/// Everything exported on `/metrics`, labelled by channel.
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    registry: Registry,
+    messages: IntCounterVec,
+    trigger_matches: IntCounterVec,
+    threshold_hits: IntCounterVec,
+    plays_sent: IntCounterVec,
+    plays_suppressed: IntCounterVec,
+    plays_late: IntCounterVec,
+    plays_withheld: IntCounterVec,
+    say_errors: IntCounterVec,
+    buffer_fill: IntGaugeVec,
+    reaction_latency: HistogramVec,
+}
+
+/// The metrics of a single channel, so the message loop doesn't look up labels on every message.
+#[derive(Clone, Debug)]
+pub struct ChannelMetrics {
+    pub messages: IntCounter,
+    pub trigger_matches: IntCounter,
+    pub threshold_hits: IntCounter,
+    pub plays_sent: IntCounter,
+    pub plays_suppressed: IntCounter,
+    pub plays_late: IntCounter,
+    pub plays_withheld: IntCounter,
+    pub say_errors: IntCounter,
+    pub buffer_fill: IntGauge,
+    pub reaction_latency: Histogram,
+}
+
+fn counter(registry: &Registry, name: &str, help: &str) -> IntCounterVec {
+    let counter = IntCounterVec::new(Opts::new(name, help), &["channel"]).unwrap();
+    registry.register(Box::new(counter.clone())).unwrap();
+    counter
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("marblejoiner".to_owned()), None).unwrap();
+        let buffer_fill = IntGaugeVec::new(
+            Opts::new(
+                "buffer_fill",
+                "Buffered messages currently matching a trigger",
+            ),
+            &["channel"],
+        )
+        .unwrap();
+        registry.register(Box::new(buffer_fill.clone())).unwrap();
+        let reaction_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "reaction_latency_seconds",
+                "Time from the first trigger match to sending the play",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["channel"],
+        )
+        .unwrap();
+        registry
+            .register(Box::new(reaction_latency.clone()))
+            .unwrap();
+
+        Metrics {
+            messages: counter(&registry, "messages_total", "Chat messages seen"),
+            trigger_matches: counter(
+                &registry,
+                "trigger_matches_total",
+                "Chat messages matching a trigger pattern",
+            ),
+            threshold_hits: counter(
+                &registry,
+                "threshold_hits_total",
+                "Times the trigger policy was met, whether or not a play followed",
+            ),
+            plays_sent: counter(&registry, "plays_sent_total", "Plays sent to chat"),
+            plays_suppressed: counter(
+                &registry,
+                "plays_suppressed_total",
+                "Triggered plays dropped because the cooldown was still active",
+            ),
+            plays_late: counter(
+                &registry,
+                "plays_late_total",
+                "Triggered plays dropped because the lobby had closed by the time they were due",
+            ),
+            plays_withheld: counter(
+                &registry,
+                "plays_withheld_total",
+                "Plays not sent because the chat mode or the rate limit wouldn't let them through",
+            ),
+            say_errors: counter(&registry, "say_errors_total", "Plays that failed to send"),
+            buffer_fill,
+            reaction_latency,
+            registry,
+        }
+    }
+
+    pub fn channel(self: &Metrics, channel: &str) -> ChannelMetrics {
+        ChannelMetrics {
+            messages: self.messages.with_label_values(&[channel]),
+            trigger_matches: self.trigger_matches.with_label_values(&[channel]),
+            threshold_hits: self.threshold_hits.with_label_values(&[channel]),
+            plays_sent: self.plays_sent.with_label_values(&[channel]),
+            plays_suppressed: self.plays_suppressed.with_label_values(&[channel]),
+            plays_late: self.plays_late.with_label_values(&[channel]),
+            plays_withheld: self.plays_withheld.with_label_values(&[channel]),
+            say_errors: self.say_errors.with_label_values(&[channel]),
+            buffer_fill: self.buffer_fill.with_label_values(&[channel]),
+            reaction_latency: self.reaction_latency.with_label_values(&[channel]),
+        }
+    }
+
+    /// Drops the series of a parted channel, so its buffer fill doesn't linger.
+    pub fn remove_channel(self: &Metrics, channel: &str) {
+        let _ = self.buffer_fill.remove_label_values(&[channel]);
+    }
+
+    /// All metrics in the Prometheus text format.
+    pub fn encode(self: &Metrics) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_channel_metrics() {
+        let metrics = Metrics::new();
+        let channel_metrics = metrics.channel("marbles");
+        channel_metrics.messages.inc();
+        channel_metrics.buffer_fill.set(3);
+        channel_metrics.reaction_latency.observe(6.0);
+
+        let encoded = metrics.encode();
+        assert!(encoded.contains("marblejoiner_messages_total{channel=\"marbles\"} 1"));
+        assert!(encoded.contains("marblejoiner_buffer_fill{channel=\"marbles\"} 3"));
+        assert!(encoded.contains(
+            "marblejoiner_reaction_latency_seconds_bucket{channel=\"marbles\",le=\"7.5\"} 1"
+        ));
+
+        metrics.remove_channel("marbles");
+        assert!(!metrics.encode().contains("marblejoiner_buffer_fill{"));
+    }
+}
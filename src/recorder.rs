@@ -0,0 +1,141 @@
+//! Records every chat message of the joined channels together with what the trigger logic made of
+//! it, to replay or analyze later on.
+//!
+//! Every channel gets one JSONL file per UTC day, named like `marbles-2023-05-01.jsonl`, with lines
+//! like
+//! `{"timestamp": "2023-05-01T20:00:00Z", "channel": "marbles", "sender": "someone", "sender_id": "12345", "text": "!play", "triggered": false, "reason": "below_threshold", "buffered_plays": 3}`.
+//! Those lines are understood by the replay and tune subcommands as they are.
+
+use crate::chat::ChatMessage;
+use crate::Decision;
+use chrono::NaiveDate;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::PathBuf;
+use tracing::warn;
+
+#[derive(Serialize, Debug)]
+struct Record<'a> {
+    #[serde(flatten)]
+    message: &'a ChatMessage,
+    triggered: bool,
+    reason: &'a str,
+    buffered_plays: usize,
+}
+
+#[derive(Debug)]
+pub struct Recorder {
+    dir: PathBuf,
+    /// The file currently written for every channel and the day it is for.
+    files: HashMap<String, (NaiveDate, LineWriter<File>)>,
+}
+
+impl Recorder {
+    pub fn new(dir: PathBuf) -> io::Result<Recorder> {
+        fs::create_dir_all(&dir)?;
+        Ok(Recorder {
+            dir,
+            files: HashMap::new(),
+        })
+    }
+
+    /// Appends `message` and the decision on it to the file of its channel and day. Failing to
+    /// write is only logged, losing a recording is no reason to stop playing.
+    pub(crate) fn record(
+        self: &mut Recorder,
+        message: &ChatMessage,
+        decision: &Decision,
+        buffered_plays: usize,
+    ) {
+        let record = Record {
+            message,
+            triggered: matches!(decision, Decision::Play(_)),
+            reason: decision.reason(),
+            buffered_plays,
+        };
+        let line = serde_json::to_string(&record).unwrap();
+        if let Err(error) = self
+            .file(&message.channel, message.timestamp.date_naive())
+            .and_then(|file| writeln!(file, "{}", line))
+        {
+            warn!(
+                channel = %message.channel,
+                dir = %self.dir.display(),
+                "could not record message: {}",
+                error
+            );
+        }
+    }
+
+    /// The file to write `channel` to on `day`, moving on to a new one once the day changed.
+    fn file(
+        self: &mut Recorder,
+        channel: &str,
+        day: NaiveDate,
+    ) -> io::Result<&mut LineWriter<File>> {
+        if !matches!(self.files.get(channel), Some((current, _)) if *current == day) {
+            let path = self
+                .dir
+                .join(format!("{}-{}.jsonl", channel, day.format("%Y-%m-%d")));
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            self.files
+                .insert(channel.to_owned(), (day, LineWriter::new(file)));
+        }
+        Ok(&mut self.files.get_mut(channel).unwrap().1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay;
+
+    fn message(timestamp: &str, sender: &str, text: &str) -> ChatMessage {
+        ChatMessage {
+            timestamp: timestamp.parse().unwrap(),
+            channel: "marbles".to_owned(),
+            sender: sender.to_owned(),
+            sender_id: format!("{}-id", sender),
+            text: text.to_owned(),
+        }
+    }
+
+    #[test]
+    fn writes_replayable_files_per_channel_and_day() {
+        let dir =
+            std::env::temp_dir().join(format!("marblejoiner-recorder-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut recorder = Recorder::new(dir.clone()).unwrap();
+
+        recorder.record(
+            &message("2023-05-01T23:59:59Z", "someone", "!play"),
+            &Decision::BelowThreshold,
+            1,
+        );
+        recorder.record(
+            &message("2023-05-02T00:00:00Z", "other", "hi"),
+            &Decision::NoMatch,
+            1,
+        );
+
+        let first = dir.join("marbles-2023-05-01.jsonl");
+        assert_eq!(
+            fs::read_to_string(&first).unwrap(),
+            "{\"timestamp\":\"2023-05-01T23:59:59Z\",\"channel\":\"marbles\",\"sender\":\"someone\",\"sender_id\":\"someone-id\",\"text\":\"!play\",\"triggered\":false,\"reason\":\"below_threshold\",\"buffered_plays\":1}\n"
+        );
+        let replayed = replay::read_log(&first).unwrap();
+        assert_eq!(
+            replayed,
+            [message("2023-05-01T23:59:59Z", "someone", "!play")]
+        );
+        assert_eq!(
+            replay::read_log(&dir.join("marbles-2023-05-02.jsonl"))
+                .unwrap()
+                .len(),
+            1
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,284 @@
+//! Replays recorded chat through the trigger logic on a virtual clock, to see when a set of
+//! settings would have played without waiting for live races.
+//!
+//! A log holds one message per line, either as a raw IRC line like Twitch sends it (the time is
+//! taken from its `tmi-sent-ts` tag) or as a JSON object like
+//! `{"timestamp": "2023-05-01T20:00:00Z", "channel": "marbles", "sender": "someone", "text": "!play"}`.
+//! Lines that aren't chat messages, e.g. `JOIN`s in a raw log, are skipped. JSON lines may carry
+//! more fields, so the files written by `--record` replay as they are, including their
+//! `sender_id`.
+
+use crate::chat::ChatMessage;
+use crate::config::{normalize_channel, ConfigError};
+use crate::{ChannelFactory, ChannelMarbleState, Decision};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use twitch_irc::message::{IRCMessage, ServerMessage};
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, usize, String),
+    Config(String, ConfigError),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(path, error) => {
+                write!(f, "could not read {}: {}", path.display(), error)
+            }
+            ReplayError::Parse(path, line, error) => {
+                write!(f, "{}:{}: {}", path.display(), line, error)
+            }
+            ReplayError::Config(channel, error) => write!(f, "{}: {}", channel, error),
+        }
+    }
+}
+
+/// A chat message in a JSONL log.
+#[derive(Deserialize, Debug)]
+struct LoggedMessage {
+    timestamp: DateTime<Utc>,
+    channel: String,
+    sender: String,
+    /// Distinct users are told apart by their login if there's no id.
+    sender_id: Option<String>,
+    text: String,
+}
+
+impl From<LoggedMessage> for ChatMessage {
+    fn from(logged: LoggedMessage) -> ChatMessage {
+        let sender = logged.sender.to_lowercase();
+        ChatMessage {
+            timestamp: logged.timestamp,
+            channel: normalize_channel(&logged.channel),
+            sender_id: logged.sender_id.unwrap_or_else(|| sender.to_owned()),
+            sender,
+            text: logged.text,
+        }
+    }
+}
+
+/// A play the replayed settings decided on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayedPlay {
+    pub channel: String,
+    pub triggered_at: DateTime<Utc>,
+    pub pattern: Option<String>,
+    pub sent_at: DateTime<Utc>,
+}
+
+impl fmt::Display for ReplayedPlay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} triggered by {}, play sent at {}",
+            self.triggered_at.to_rfc3339(),
+            self.channel,
+            self.pattern.as_deref().unwrap_or("an announcement"),
+            self.sent_at.to_rfc3339()
+        )
+    }
+}
+
+pub fn read_log(path: &Path) -> Result<Vec<ChatMessage>, ReplayError> {
+    let contents = fs::read_to_string(path).map_err(|error| ReplayError::Io(path.into(), error))?;
+    let mut messages = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        match parse_line(line) {
+            Ok(Some(message)) => messages.push(message),
+            Ok(None) => {}
+            Err(error) => return Err(ReplayError::Parse(path.into(), index + 1, error)),
+        }
+    }
+    Ok(messages)
+}
+
+fn parse_line(line: &str) -> Result<Option<ChatMessage>, String> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.starts_with('{') {
+        let logged: LoggedMessage =
+            serde_json::from_str(line).map_err(|error| error.to_string())?;
+        return Ok(Some(logged.into()));
+    }
+    let message = IRCMessage::parse(line).map_err(|error| error.to_string())?;
+    match ServerMessage::try_from(message) {
+        Ok(ServerMessage::Privmsg(message)) => Ok(Some(message.into())),
+        Ok(_) => Ok(None),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+/// Feeds `messages` through fresh channel states in the order of their timestamps, moving the
+/// factory's virtual clock along, and returns every play in order. All channels share the clock,
+/// so logs of several channels may be given one after another.
+pub fn replay(
+    messages: &[ChatMessage],
+    channel_factory: &ChannelFactory,
+) -> Result<Vec<ReplayedPlay>, ReplayError> {
+    let mut messages: Vec<&ChatMessage> = messages.iter().collect();
+    messages.sort_by_key(|message| message.timestamp);
+    let mut marble_states: HashMap<String, ChannelMarbleState> = HashMap::new();
+    let mut plays = Vec::new();
+    let mut last_timestamp = messages.first().map(|message| message.timestamp);
+    for message in messages {
+        if let Some(last_timestamp) = last_timestamp {
+            if let Ok(elapsed) = (message.timestamp - last_timestamp).to_std() {
+                channel_factory.clock.advance(elapsed);
+            }
+        }
+        last_timestamp = Some(message.timestamp);
+
+        let channel = message.channel.to_owned();
+        if !marble_states.contains_key(&channel) {
+            let marble_state = channel_factory
+                .create(channel.to_owned())
+                .map_err(|error| ReplayError::Config(channel.to_owned(), error))?;
+            marble_states.insert(channel.to_owned(), marble_state);
+        }
+        let marble_state = marble_states.get_mut(&channel).unwrap();
+        if let Decision::Play(play) = marble_state.process_message(message) {
+            plays.push(ReplayedPlay {
+                channel,
+                triggered_at: message.timestamp,
+                pattern: marble_state
+                    .last_trigger
+                    .as_ref()
+                    .and_then(|trigger| trigger.pattern.to_owned()),
+                sent_at: message.timestamp
+                    + chrono::Duration::from_std(play.wait)
+                        .expect("waits are bounded by config::MAX_WAIT"),
+            });
+        }
+    }
+    Ok(plays)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ChannelConfig;
+
+    /// A JSONL line sent `second` seconds into the log.
+    fn logged(second: u32, sender: &str, text: &str) -> String {
+        logged_in("Marbles", second, sender, text)
+    }
+
+    fn logged_in(channel: &str, second: u32, sender: &str, text: &str) -> String {
+        format!(
+            r#"{{"timestamp": "2023-05-01T20:00:{:02}Z", "channel": "{}", "sender": "{}", "text": "{}"}}"#,
+            second, channel, sender, text
+        )
+    }
+
+    #[test]
+    fn parses_raw_and_json_lines() {
+        let raw = parse_line("@badge-info=;badges=;color=;display-name=Someone;emotes=;flags=;id=d7f03a35-f339-41ca-b4d4-7c0721438570;mod=0;room-id=11148817;subscriber=0;tmi-sent-ts=1594571566672;turbo=0;user-id=36175310;user-type= :someone!someone@someone.tmi.twitch.tv PRIVMSG #marbles :!play")
+            .unwrap()
+            .unwrap();
+        assert_eq!(raw.channel, "marbles");
+        assert_eq!(raw.timestamp.timestamp_millis(), 1594571566672);
+
+        let json = parse_line(&logged(5, "Someone", "!play now"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(json.channel, "marbles");
+        assert_eq!(json.sender, "someone");
+        assert_eq!(json.sender_id, "someone");
+        assert_eq!(json.text, "!play now");
+        assert_eq!(json.timestamp.to_rfc3339(), "2023-05-01T20:00:05+00:00");
+
+        assert!(parse_line(":tmi.twitch.tv PING").unwrap().is_none());
+        assert!(parse_line("").unwrap().is_none());
+        assert!(parse_line("{\"timestamp\": 5}").is_err());
+    }
+
+    #[test]
+    fn reports_plays_and_respects_the_cooldown_in_log_time() {
+        let channel_factory = ChannelFactory::for_test(ChannelConfig {
+            treshhold: Some(2),
+            delay: Some(30),
+            wait: Some(5),
+            ..ChannelConfig::default()
+        });
+        let lines = [
+            logged(0, "a", "!play"),
+            logged(1, "b", "!play"),
+            // cooling down until second 31
+            logged(10, "c", "!play"),
+            logged(11, "d", "!play"),
+            logged(31, "e", "!play"),
+            logged(32, "f", "hi"),
+            logged(33, "g", "!play"),
+        ];
+        let messages: Vec<ChatMessage> = lines
+            .iter()
+            .map(|line| parse_line(line).unwrap().unwrap())
+            .collect();
+
+        let plays = replay(&messages, &channel_factory).unwrap();
+
+        let times: Vec<String> = plays
+            .iter()
+            .map(|play| {
+                format!(
+                    "{} {}",
+                    play.triggered_at.format("%S"),
+                    play.sent_at.format("%S")
+                )
+            })
+            .collect();
+        assert_eq!(times, ["01 06", "31 36"]);
+        assert_eq!(plays[0].pattern.as_deref(), Some("^!play"));
+    }
+
+    #[test]
+    fn replays_the_logs_of_several_channels_one_after_another() {
+        let channel_factory = ChannelFactory::for_test(ChannelConfig {
+            treshhold: Some(2),
+            delay: Some(30),
+            wait: Some(5),
+            ..ChannelConfig::default()
+        });
+        let lines = [
+            logged_in("Marbles", 0, "a", "!play"),
+            logged_in("Marbles", 1, "b", "!play"),
+            logged_in("Marbles", 40, "c", "!play"),
+            logged_in("Marbles", 41, "d", "!play"),
+            // the second channel's log starts over at the beginning
+            logged_in("Pixelbypixel", 0, "a", "!play"),
+            logged_in("Pixelbypixel", 1, "b", "!play"),
+            logged_in("Pixelbypixel", 35, "c", "!play"),
+            logged_in("Pixelbypixel", 36, "d", "!play"),
+        ];
+        let messages: Vec<ChatMessage> = lines
+            .iter()
+            .map(|line| parse_line(line).unwrap().unwrap())
+            .collect();
+
+        let plays = replay(&messages, &channel_factory).unwrap();
+
+        let times: Vec<String> = plays
+            .iter()
+            .map(|play| format!("{} {}", play.channel, play.triggered_at.format("%S")))
+            .collect();
+        assert_eq!(
+            times,
+            [
+                "marbles 01",
+                "pixelbypixel 01",
+                "pixelbypixel 36",
+                "marbles 41"
+            ]
+        );
+    }
+}
@@ -0,0 +1,458 @@
+use crate::chat::{ChatEvent, ChatSink};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelHealth {
+    Joining,
+    Joined,
+    Parted,
+    Failed(String),
+}
+
+impl fmt::Display for ChannelHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelHealth::Joining => write!(f, "joining"),
+            ChannelHealth::Joined => write!(f, "joined"),
+            ChannelHealth::Parted => write!(f, "parted"),
+            ChannelHealth::Failed(reason) => write!(f, "failed ({})", reason),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ChannelConnection {
+    health: ChannelHealth,
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+/// Keeps every channel joined: watches for confirmed joins, parts, failed joins and reconnects,
+/// and re-sends `JOIN`s with exponential backoff until the server confirms them.
+#[derive(Debug)]
+pub struct JoinSupervisor {
+    channels: HashMap<String, ChannelConnection>,
+    initial_backoff: Duration,
+    changed: bool,
+}
+
+impl JoinSupervisor {
+    pub fn new<'a>(channels: impl IntoIterator<Item = &'a String>) -> JoinSupervisor {
+        let now = Instant::now();
+        JoinSupervisor {
+            channels: channels
+                .into_iter()
+                .map(|channel| {
+                    (
+                        channel.to_owned(),
+                        ChannelConnection {
+                            health: ChannelHealth::Joining,
+                            attempts: 0,
+                            next_attempt: now,
+                        },
+                    )
+                })
+                .collect(),
+            initial_backoff: INITIAL_BACKOFF,
+            changed: true,
+        }
+    }
+
+    /// Starts keeping `channel` joined, its `JOIN` goes out on the next `check`.
+    pub fn add(self: &mut JoinSupervisor, channel: &str) {
+        self.channels
+            .entry(channel.to_owned())
+            .or_insert_with(|| ChannelConnection {
+                health: ChannelHealth::Joining,
+                attempts: 0,
+                next_attempt: Instant::now(),
+            });
+        self.changed = true;
+    }
+
+    /// Stops keeping `channel` joined, leaving the `PART` to the caller.
+    pub fn remove(self: &mut JoinSupervisor, channel: &str) {
+        if self.channels.remove(channel).is_some() {
+            self.changed = true;
+        }
+    }
+
+    pub fn health(self: &JoinSupervisor, channel: &str) -> Option<&ChannelHealth> {
+        self.channels
+            .get(channel)
+            .map(|connection| &connection.health)
+    }
+
+    pub fn is_healthy(self: &JoinSupervisor) -> bool {
+        self.channels
+            .values()
+            .all(|connection| connection.health == ChannelHealth::Joined)
+    }
+
+    /// A plain text report: `ok` or `degraded`, followed by one line per channel.
+    pub fn report(self: &JoinSupervisor) -> String {
+        let mut channels: Vec<(&String, &ChannelConnection)> = self.channels.iter().collect();
+        channels.sort_by_key(|(channel, _)| *channel);
+        let mut report = String::from(if self.is_healthy() {
+            "ok\n"
+        } else {
+            "degraded\n"
+        });
+        for (channel, connection) in channels {
+            report.push_str(&format!("{} {}\n", channel, connection.health));
+        }
+        report
+    }
+
+    /// Returns whether any channel changed its health since the last call.
+    pub fn take_changed(self: &mut JoinSupervisor) -> bool {
+        std::mem::replace(&mut self.changed, false)
+    }
+
+    pub fn on_event(self: &mut JoinSupervisor, event: &ChatEvent) {
+        let now = Instant::now();
+        match event {
+            ChatEvent::Joined(channel_login) => {
+                if let Some(connection) = self.channels.get_mut(channel_login) {
+                    connection.attempts = 0;
+                }
+                self.transition(channel_login, ChannelHealth::Joined);
+            }
+            ChatEvent::Parted(channel_login) => {
+                self.schedule_retry(channel_login, now);
+                self.transition(channel_login, ChannelHealth::Parted);
+            }
+            ChatEvent::JoinFailed(channel_login, reason) => {
+                self.transition(channel_login, ChannelHealth::Failed(reason.to_owned()));
+            }
+            ChatEvent::Reconnect => {
+                info!("server requested a reconnect, rejoining all channels");
+                // the sink rejoins by itself on the new connection, only step in if it doesn't
+                let joined: Vec<String> = self
+                    .channels
+                    .iter()
+                    .filter(|(_, connection)| connection.health == ChannelHealth::Joined)
+                    .map(|(channel, _)| channel.to_owned())
+                    .collect();
+                for channel in joined {
+                    self.schedule_retry(&channel, now);
+                    self.transition(&channel, ChannelHealth::Joining);
+                }
+            }
+            ChatEvent::Message(_) | ChatEvent::RoomState(..) | ChatEvent::UserState(..) => {}
+        }
+    }
+
+    /// Polls the sink for channels that silently lost their join, e.g. on a dropped
+    /// connection, and re-sends the `JOIN` for every channel whose retry is due.
+    pub async fn check<S: ChatSink>(self: &mut JoinSupervisor, sink: &S) {
+        let now = Instant::now();
+        let channels: Vec<String> = self.channels.keys().cloned().collect();
+        for channel in channels {
+            let joined = sink.is_joined(channel.to_owned()).await;
+            match self.health(&channel) {
+                Some(ChannelHealth::Joined) if !joined => {
+                    self.schedule_retry(&channel, now);
+                    self.transition(&channel, ChannelHealth::Joining);
+                }
+                Some(ChannelHealth::Joining) if joined => {
+                    self.transition(&channel, ChannelHealth::Joined);
+                }
+                _ => {}
+            }
+        }
+
+        for channel in self.due_joins(now) {
+            if let Err(error) = sink.join(channel.to_owned()) {
+                self.transition(&channel, ChannelHealth::Failed(error.to_string()));
+            }
+        }
+    }
+
+    fn due_joins(self: &mut JoinSupervisor, now: Instant) -> Vec<String> {
+        let due: Vec<String> = self
+            .channels
+            .iter()
+            .filter(|(_, connection)| {
+                connection.health != ChannelHealth::Joined && connection.next_attempt <= now
+            })
+            .map(|(channel, _)| channel.to_owned())
+            .collect();
+        for channel in due.iter() {
+            self.schedule_retry(channel, now);
+            if self.health(channel) == Some(&ChannelHealth::Parted) {
+                self.transition(channel, ChannelHealth::Joining);
+            }
+        }
+        due
+    }
+
+    fn schedule_retry(self: &mut JoinSupervisor, channel: &str, now: Instant) {
+        let initial_backoff = self.initial_backoff;
+        if let Some(connection) = self.channels.get_mut(channel) {
+            let backoff = initial_backoff
+                .saturating_mul(2u32.saturating_pow(connection.attempts))
+                .min(MAX_BACKOFF);
+            connection.attempts = connection.attempts.saturating_add(1);
+            connection.next_attempt = now + backoff;
+        }
+    }
+
+    fn transition(self: &mut JoinSupervisor, channel: &str, health: ChannelHealth) {
+        if let Some(connection) = self.channels.get_mut(channel) {
+            if connection.health != health {
+                info!(
+                    channel = %channel,
+                    from = %connection.health,
+                    to = %health,
+                    "channel health changed"
+                );
+                connection.health = health;
+                self.changed = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::ChatSource;
+    use crate::fake_irc::{FakeClient, FakeServer};
+    use twitch_irc::{login::StaticLoginCredentials, message::ServerMessage, ClientConfig};
+
+    fn new_client(
+        server: &FakeServer,
+    ) -> (
+        tokio::sync::mpsc::UnboundedReceiver<ServerMessage>,
+        FakeClient,
+    ) {
+        server.use_for_this_thread();
+        let config = ClientConfig {
+            new_connection_every: Duration::from_millis(10),
+            ..ClientConfig::new_simple(StaticLoginCredentials::anonymous())
+        };
+        FakeClient::new(config)
+    }
+
+    fn new_supervisor(channel: &str) -> JoinSupervisor {
+        let mut supervisor = JoinSupervisor::new([&channel.to_owned()]);
+        supervisor.initial_backoff = Duration::from_millis(50);
+        supervisor
+    }
+
+    /// Runs the supervisor like `main` does until `done` holds, failing after a few seconds.
+    async fn run_until(
+        supervisor: &mut JoinSupervisor,
+        client: &FakeClient,
+        incoming_messages: &mut tokio::sync::mpsc::UnboundedReceiver<ServerMessage>,
+        done: impl Fn(&JoinSupervisor) -> bool,
+    ) {
+        let run = async {
+            let mut check_interval = tokio::time::interval(Duration::from_millis(10));
+            while !done(supervisor) {
+                tokio::select! {
+                    Some(event) = incoming_messages.next_event() => supervisor.on_event(&event),
+                    _ = check_interval.tick() => supervisor.check(client).await,
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .expect("supervisor did not reach the expected state");
+    }
+
+    #[tokio::test]
+    async fn joins_channels_and_reports_healthy() {
+        let mut server = FakeServer::start().await;
+        let (mut incoming_messages, client) = new_client(&server);
+        let mut supervisor = new_supervisor("marbles");
+        assert!(!supervisor.is_healthy());
+
+        let script = tokio::spawn(async move {
+            let mut connection = server.accept().await;
+            connection.expect("JOIN #marbles").await;
+            connection.confirm_join("marbles").await;
+            server
+        });
+        run_until(
+            &mut supervisor,
+            &client,
+            &mut incoming_messages,
+            |supervisor| supervisor.is_healthy(),
+        )
+        .await;
+        script.await.unwrap();
+
+        assert_eq!(supervisor.report(), "ok\nmarbles joined\n");
+        assert!(supervisor.take_changed());
+        assert!(!supervisor.take_changed());
+    }
+
+    #[tokio::test]
+    async fn retries_failed_join_with_backoff() {
+        let mut server = FakeServer::start().await;
+        let (mut incoming_messages, client) = new_client(&server);
+        let mut supervisor = new_supervisor("marbles");
+
+        let script = tokio::spawn(async move {
+            let mut connection = server.accept().await;
+            connection.expect("JOIN #marbles").await;
+            connection
+                .send("@msg-id=msg_channel_suspended :tmi.twitch.tv NOTICE #marbles :This channel has been suspended.")
+                .await;
+            let failed_at = Instant::now();
+            connection.expect("JOIN #marbles").await;
+            let retried_after = failed_at.elapsed();
+            connection.confirm_join("marbles").await;
+            (server, retried_after)
+        });
+        run_until(
+            &mut supervisor,
+            &client,
+            &mut incoming_messages,
+            |supervisor| matches!(supervisor.health("marbles"), Some(ChannelHealth::Failed(_))),
+        )
+        .await;
+        assert_eq!(
+            supervisor.report(),
+            "degraded\nmarbles failed (This channel has been suspended.)\n"
+        );
+
+        run_until(
+            &mut supervisor,
+            &client,
+            &mut incoming_messages,
+            |supervisor| supervisor.is_healthy(),
+        )
+        .await;
+        let (_server, retried_after) = script.await.unwrap();
+        assert!(retried_after >= Duration::from_millis(30));
+    }
+
+    #[tokio::test]
+    async fn rejoins_after_reconnect() {
+        let mut server = FakeServer::start().await;
+        let (mut incoming_messages, client) = new_client(&server);
+        let mut supervisor = new_supervisor("marbles");
+
+        let script = tokio::spawn(async move {
+            let mut connection = server.accept().await;
+            connection.expect("JOIN #marbles").await;
+            connection.confirm_join("marbles").await;
+            connection.send(":tmi.twitch.tv RECONNECT").await;
+
+            let mut connection = server.accept().await;
+            connection.expect("JOIN #marbles").await;
+            connection.confirm_join("marbles").await;
+            server
+        });
+        run_until(
+            &mut supervisor,
+            &client,
+            &mut incoming_messages,
+            |supervisor| supervisor.is_healthy(),
+        )
+        .await;
+        run_until(
+            &mut supervisor,
+            &client,
+            &mut incoming_messages,
+            |supervisor| !supervisor.is_healthy(),
+        )
+        .await;
+        assert_eq!(supervisor.health("marbles"), Some(&ChannelHealth::Joining));
+
+        run_until(
+            &mut supervisor,
+            &client,
+            &mut incoming_messages,
+            |supervisor| supervisor.is_healthy(),
+        )
+        .await;
+        script.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejoins_after_being_parted() {
+        let mut server = FakeServer::start().await;
+        let (mut incoming_messages, client) = new_client(&server);
+        let mut supervisor = new_supervisor("marbles");
+
+        let script = tokio::spawn(async move {
+            let mut connection = server.accept().await;
+            connection.expect("JOIN #marbles").await;
+            connection.confirm_join("marbles").await;
+            connection
+                .send(":justinfan12345!justinfan12345@justinfan12345.tmi.twitch.tv PART #marbles")
+                .await;
+            connection.expect("JOIN #marbles").await;
+            connection.confirm_join("marbles").await;
+            server
+        });
+        run_until(
+            &mut supervisor,
+            &client,
+            &mut incoming_messages,
+            |supervisor| supervisor.is_healthy(),
+        )
+        .await;
+        run_until(
+            &mut supervisor,
+            &client,
+            &mut incoming_messages,
+            |supervisor| supervisor.health("marbles") == Some(&ChannelHealth::Parted),
+        )
+        .await;
+        run_until(
+            &mut supervisor,
+            &client,
+            &mut incoming_messages,
+            |supervisor| supervisor.is_healthy(),
+        )
+        .await;
+        script.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn joins_channels_added_at_runtime() {
+        let mut server = FakeServer::start().await;
+        let (mut incoming_messages, client) = new_client(&server);
+        let mut supervisor = new_supervisor("marbles");
+
+        let script = tokio::spawn(async move {
+            let mut connection = server.accept().await;
+            connection.expect("JOIN #marbles").await;
+            connection.confirm_join("marbles").await;
+            connection.expect("JOIN #pixelbypixel").await;
+            connection.confirm_join("pixelbypixel").await;
+            server
+        });
+        run_until(
+            &mut supervisor,
+            &client,
+            &mut incoming_messages,
+            |supervisor| supervisor.is_healthy(),
+        )
+        .await;
+        supervisor.add("pixelbypixel");
+        assert!(!supervisor.is_healthy());
+        run_until(
+            &mut supervisor,
+            &client,
+            &mut incoming_messages,
+            |supervisor| supervisor.is_healthy(),
+        )
+        .await;
+        script.await.unwrap();
+
+        supervisor.remove("marbles");
+        assert_eq!(supervisor.report(), "ok\npixelbypixel joined\n");
+    }
+}
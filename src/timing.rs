@@ -0,0 +1,152 @@
+use crate::config::ChannelParams;
+use chrono::{DateTime, Utc};
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+
+/// How many of the last bursts the join timing is learned from.
+const MAX_BURSTS: usize = 20;
+/// How many bursts have to be seen before the learned timing is trusted over `wait`.
+const MIN_BURSTS: usize = 3;
+
+/// Learns how long after the first trigger match of a burst the chatters of a channel usually
+/// join, from the server timestamps of their messages, so plays can be sent when most of them do.
+#[derive(Debug, Default)]
+pub struct JoinTiming {
+    current: Option<Burst>,
+    /// Join offsets of the last finished bursts, oldest first.
+    history: VecDeque<Vec<Duration>>,
+}
+
+/// The trigger matches following a first one, each chatter counted once.
+#[derive(Debug)]
+struct Burst {
+    started_at: DateTime<Utc>,
+    senders: HashSet<String>,
+    /// How long after the start every chatter joined.
+    offsets: Vec<Duration>,
+}
+
+impl JoinTiming {
+    /// Notes a trigger match. A match more than `lobby_duration` after the start of the current
+    /// burst starts a new one, finishing the current one unless fewer than `treshhold` chatters
+    /// took part in it, which is just noise.
+    pub fn on_match(
+        self: &mut JoinTiming,
+        at: DateTime<Utc>,
+        sender: &str,
+        params: &ChannelParams,
+    ) {
+        let lobby_duration = chrono::Duration::from_std(params.lobby_duration)
+            .unwrap_or_else(|_| chrono::Duration::max_value());
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|burst| at - burst.started_at > lobby_duration)
+        {
+            let burst = self.current.take().unwrap();
+            if burst.senders.len() >= params.treshhold {
+                self.history.push_back(burst.offsets);
+                if self.history.len() > MAX_BURSTS {
+                    self.history.pop_front();
+                }
+            }
+        }
+        let burst = self.current.get_or_insert_with(|| Burst {
+            started_at: at,
+            senders: HashSet::new(),
+            offsets: Vec::new(),
+        });
+        if burst.senders.insert(sender.to_owned()) {
+            burst
+                .offsets
+                .push((at - burst.started_at).to_std().unwrap_or_default());
+        }
+    }
+
+    /// The `percentile` of the join offsets of all learned bursts, `None` until there are enough.
+    pub fn percentile(self: &JoinTiming, percentile: f64) -> Option<Duration> {
+        if self.history.len() < MIN_BURSTS {
+            return None;
+        }
+        let mut offsets: Vec<Duration> = self.history.iter().flatten().copied().collect();
+        offsets.sort();
+        let rank = (percentile / 100.0 * offsets.len() as f64).ceil() as usize;
+        offsets.get(rank.clamp(1, offsets.len()) - 1).copied()
+    }
+
+    /// How long to wait from `now` until the `wait_percentile` of the learned join offsets into the
+    /// current burst, moved by up to `wait_jitter` either way. `None` if no percentile is set or
+    /// there isn't enough history yet, in which case the fixed `wait` applies.
+    pub fn wait(self: &JoinTiming, now: DateTime<Utc>, params: &ChannelParams) -> Option<Duration> {
+        let target = self.percentile(params.wait_percentile?)?;
+        let elapsed = self
+            .current
+            .as_ref()
+            .and_then(|burst| (now - burst.started_at).to_std().ok())
+            .unwrap_or_default();
+        let jitter = params.wait_jitter.as_secs_f64() * (fastrand::f64() * 2.0 - 1.0);
+        Some(Duration::from_secs_f64(
+            (target.as_secs_f64() - elapsed.as_secs_f64() + jitter).max(0.0),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ChannelConfig;
+    use crate::ChannelFactory;
+
+    fn params(wait_percentile: Option<f64>, wait_jitter: Option<f64>) -> ChannelParams {
+        ChannelFactory::for_test(ChannelConfig {
+            treshhold: Some(2),
+            lobby_duration: Some(60),
+            wait_percentile,
+            wait_jitter,
+            ..ChannelConfig::default()
+        })
+        .resolve("marbles")
+        .unwrap()
+    }
+
+    fn time(second: i64) -> DateTime<Utc> {
+        "2023-05-01T20:00:00Z".parse::<DateTime<Utc>>().unwrap() + chrono::Duration::seconds(second)
+    }
+
+    #[test]
+    fn learns_join_offsets_from_bursts() {
+        let median = params(Some(50.0), None);
+        let mut timing = JoinTiming::default();
+        // three races with chatters joining 0, 4, 8 and 12 seconds in, and a lone spammer after
+        // each of them
+        for race in 0..3 {
+            for (index, sender) in ["a", "b", "c", "d"].iter().enumerate() {
+                let at = time(race * 200 + index as i64 * 4);
+                timing.on_match(at, sender, &median);
+                timing.on_match(at, "a", &median);
+            }
+            assert_eq!(timing.wait(time(race * 200 + 2), &median), None);
+            timing.on_match(time(race * 200 + 62), "spammer", &median);
+        }
+        timing.on_match(time(600), "a", &median);
+        timing.on_match(time(603), "b", &median);
+
+        assert_eq!(timing.percentile(50.0), Some(Duration::from_secs(4)));
+        assert_eq!(timing.percentile(90.0), Some(Duration::from_secs(12)));
+        assert_eq!(timing.percentile(0.0), Some(Duration::ZERO));
+        assert_eq!(
+            timing.wait(time(603), &median),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            timing.wait(time(603), &params(Some(90.0), None)),
+            Some(Duration::from_secs(9))
+        );
+        assert_eq!(timing.wait(time(603), &params(None, None)), None);
+
+        let jittered = timing
+            .wait(time(603), &params(Some(90.0), Some(2.0)))
+            .unwrap();
+        assert!(jittered >= Duration::from_secs(7) && jittered <= Duration::from_secs(11));
+    }
+}
@@ -0,0 +1,236 @@
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use twitch_irc::login::{TokenStorage, UserAccessToken};
+
+/// The token file: the app's client credentials next to the user token they refresh. Leaving out
+/// `created_at` marks the token as expired, so a file holding nothing but a fresh refresh token
+/// gets refreshed on the first connect.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TokenFile {
+    client_id: String,
+    client_secret: String,
+    access_token: String,
+    refresh_token: String,
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+pub enum TokenStorageError {
+    Io(PathBuf, io::Error),
+    Json(PathBuf, serde_json::Error),
+    WorldReadable(PathBuf),
+}
+
+impl fmt::Display for TokenStorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenStorageError::Io(path, error) => {
+                write!(f, "could not access {}: {}", path.display(), error)
+            }
+            TokenStorageError::Json(path, error) => {
+                write!(f, "invalid token file {}: {}", path.display(), error)
+            }
+            TokenStorageError::WorldReadable(path) => write!(
+                f,
+                "{} is readable by everyone, restrict it with chmod o-r first",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// Keeps the user token in a local JSON file, rewriting it whenever twitch-irc refreshes it.
+#[derive(Debug)]
+pub struct JsonFileTokenStorage {
+    path: PathBuf,
+    client_id: String,
+    client_secret: String,
+}
+
+impl JsonFileTokenStorage {
+    /// Opens the token file at `path`, checking it is usable before the first connect needs it.
+    pub fn open(path: &Path) -> Result<JsonFileTokenStorage, TokenStorageError> {
+        let token_file = read_token_file(path)?;
+        Ok(JsonFileTokenStorage {
+            path: path.to_owned(),
+            client_id: token_file.client_id,
+            client_secret: token_file.client_secret,
+        })
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn client_secret(&self) -> &str {
+        &self.client_secret
+    }
+}
+
+fn read_token_file(path: &Path) -> Result<TokenFile, TokenStorageError> {
+    let metadata = fs::metadata(path).map_err(|error| TokenStorageError::Io(path.into(), error))?;
+    if crate::credentials::is_world_readable(&metadata) {
+        return Err(TokenStorageError::WorldReadable(path.into()));
+    }
+    let contents =
+        fs::read_to_string(path).map_err(|error| TokenStorageError::Io(path.into(), error))?;
+    serde_json::from_str(&contents).map_err(|error| TokenStorageError::Json(path.into(), error))
+}
+
+/// Replaces the file in one step, so a crash mid-write never loses the refresh token.
+fn write_token_file(path: &Path, token_file: &TokenFile) -> Result<(), TokenStorageError> {
+    let contents = serde_json::to_string_pretty(token_file)
+        .map_err(|error| TokenStorageError::Json(path.into(), error))?;
+    let temporary_path = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let write = || -> io::Result<()> {
+        io::Write::write_all(&mut options.open(&temporary_path)?, contents.as_bytes())?;
+        fs::rename(&temporary_path, path)
+    };
+    write().map_err(|error| TokenStorageError::Io(path.into(), error))
+}
+
+#[async_trait]
+impl TokenStorage for JsonFileTokenStorage {
+    type LoadError = TokenStorageError;
+    type UpdateError = TokenStorageError;
+
+    async fn load_token(&mut self) -> Result<UserAccessToken, TokenStorageError> {
+        let token_file = read_token_file(&self.path)?;
+        Ok(UserAccessToken {
+            access_token: token_file.access_token,
+            refresh_token: token_file.refresh_token,
+            created_at: token_file
+                .created_at
+                .unwrap_or_else(|| Utc.timestamp_opt(0, 0).unwrap()),
+            expires_at: token_file.expires_at,
+        })
+    }
+
+    async fn update_token(&mut self, token: &UserAccessToken) -> Result<(), TokenStorageError> {
+        write_token_file(
+            &self.path,
+            &TokenFile {
+                client_id: self.client_id.to_owned(),
+                client_secret: self.client_secret.to_owned(),
+                access_token: token.access_token.to_owned(),
+                refresh_token: token.refresh_token.to_owned(),
+                created_at: Some(token.created_at),
+                expires_at: token.expires_at,
+            },
+        )
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::credentials::Credentials;
+    use std::os::unix::fs::PermissionsExt;
+    use twitch_irc::login::{GetAccessTokenResponse, LoginCredentials};
+
+    fn token_file(name: &str, contents: &str, mode: u32) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("marblejoiner-{}-{}.json", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    const BOOTSTRAP: &str = r#"{
+        "client_id": "someid",
+        "client_secret": "somesecret",
+        "access_token": "",
+        "refresh_token": "firstrefresh"
+    }"#;
+
+    #[tokio::test]
+    async fn bootstrap_token_is_expired() {
+        let path = token_file("bootstrap", BOOTSTRAP, 0o600);
+        let mut storage = JsonFileTokenStorage::open(&path).unwrap();
+        let token = storage.load_token().await.unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(storage.client_id(), "someid");
+        assert_eq!(storage.client_secret(), "somesecret");
+        assert_eq!(token.refresh_token, "firstrefresh");
+        assert_eq!(token.created_at.timestamp(), 0);
+        // without an expiry twitch-irc assumes a day, so it refreshes on the first connect
+        assert_eq!(token.expires_at, None);
+    }
+
+    #[tokio::test]
+    async fn refreshed_token_round_trips_and_keeps_client_credentials() {
+        let path = token_file("refreshed", BOOTSTRAP, 0o600);
+        let mut storage = JsonFileTokenStorage::open(&path).unwrap();
+        // what twitch-irc turns the token endpoint's answer into before handing it to us
+        let refreshed = UserAccessToken::from(GetAccessTokenResponse {
+            access_token: "newaccess".to_owned(),
+            refresh_token: "newrefresh".to_owned(),
+            expires_in: Some(14400),
+        });
+        storage.update_token(&refreshed).await.unwrap();
+
+        let token = storage.load_token().await.unwrap();
+        let reopened = JsonFileTokenStorage::open(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(token.access_token, "newaccess");
+        assert_eq!(token.refresh_token, "newrefresh");
+        assert_eq!(token.created_at, refreshed.created_at);
+        assert_eq!(token.expires_at, refreshed.expires_at);
+        assert_eq!(reopened.client_secret(), "somesecret");
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    /// twitch-irc posts refreshes to a hard-coded `https://id.twitch.tv/oauth2/token`, so no
+    /// local endpoint can stand in for it. This picks up once it answered: connecting uses the
+    /// stored answer without refreshing again.
+    #[tokio::test]
+    async fn connects_with_the_refreshed_token() {
+        let path = token_file("connect", BOOTSTRAP, 0o600);
+        let mut storage = JsonFileTokenStorage::open(&path).unwrap();
+        storage
+            .update_token(&UserAccessToken::from(GetAccessTokenResponse {
+                access_token: "newaccess".to_owned(),
+                refresh_token: "newrefresh".to_owned(),
+                expires_in: Some(14400),
+            }))
+            .await
+            .unwrap();
+        let stored = fs::read_to_string(&path).unwrap();
+
+        let credentials = Credentials::refreshing(
+            "someone".to_owned(),
+            JsonFileTokenStorage::open(&path).unwrap(),
+        );
+        let pair = credentials.get_credentials().await.unwrap();
+        let after_connect = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(pair.login, "someone");
+        assert_eq!(pair.token.as_deref(), Some("newaccess"));
+        assert_eq!(after_connect, stored);
+    }
+
+    #[test]
+    fn refuses_world_readable_token_file() {
+        let path = token_file("public", BOOTSTRAP, 0o644);
+        let storage = JsonFileTokenStorage::open(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(storage, Err(TokenStorageError::WorldReadable(_))));
+    }
+}
@@ -0,0 +1,187 @@
+//! Connections to an IRC server other than Twitch's, e.g. one given with `--server` or a local
+//! stand-in in tests. twitch-irc's transports only know Twitch's hosts, so these plug another
+//! server into them through the traits they take the host from.
+//!
+//! twitch-irc makes every connection through a function without arguments, so a client can't be
+//! handed its server. Instead each transport is parameterized with an `Endpoint`, a type whose
+//! implementation knows where to look the server up.
+
+use async_trait::async_trait;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Mutex;
+use tokio::net::TcpStream;
+use tokio_native_tls::native_tls;
+use twitch_irc::transport::tcp::{MakeConnection, TCPTransportConnectError};
+use twitch_irc::transport::websocket::ConnectionUri;
+
+#[derive(clap::ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Tcp,
+    #[default]
+    Tls,
+    Ws,
+    Wss,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Server {
+    pub host: String,
+    pub port: u16,
+}
+
+/// `host:port`, with IPv6 hosts in brackets so it also works as the authority of a URI.
+impl fmt::Display for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Parses `host:port`, for `--server`. IPv6 hosts may be given in brackets.
+pub fn parse_server(server: &str) -> Result<Server, String> {
+    let (host, port) = server
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected host:port, got {}", server))?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() {
+        return Err(format!("missing host in {}", server));
+    }
+    let port = port
+        .parse()
+        .map_err(|error| format!("invalid port {}: {}", port, error))?;
+    Ok(Server {
+        host: host.to_owned(),
+        port,
+    })
+}
+
+/// Where the transports of this module connect to.
+pub trait Endpoint: Send + Sync + 'static {
+    /// Looked up on every connect, including reconnects.
+    fn server() -> Server;
+}
+
+/// Plain TCP to the server of `E`.
+pub struct Plain<E: Endpoint>(PhantomData<E>);
+
+#[async_trait]
+impl<E: Endpoint> MakeConnection for Plain<E> {
+    type Socket = TcpStream;
+
+    async fn new_socket() -> Result<TcpStream, TCPTransportConnectError> {
+        let server = E::server();
+        Ok(TcpStream::connect((server.host.as_str(), server.port)).await?)
+    }
+}
+
+/// TCP secured with TLS to the server of `E`, whose certificate has to be valid for its host.
+pub struct Tls<E: Endpoint>(PhantomData<E>);
+
+#[async_trait]
+impl<E: Endpoint> MakeConnection for Tls<E> {
+    type Socket = tokio_native_tls::TlsStream<TcpStream>;
+
+    async fn new_socket() -> Result<Self::Socket, TCPTransportConnectError> {
+        let server = E::server();
+        let socket = TcpStream::connect((server.host.as_str(), server.port)).await?;
+        let connector = tokio_native_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
+        Ok(connector.connect(&server.host, socket).await?)
+    }
+}
+
+/// Plain WebSocket to the server of `E`.
+pub struct Ws<E: Endpoint>(PhantomData<E>);
+
+impl<E: Endpoint> ConnectionUri for Ws<E> {
+    fn get_server_uri() -> &'static str {
+        intern(format!("ws://{}", E::server()))
+    }
+}
+
+/// WebSocket secured with TLS to the server of `E`.
+pub struct Wss<E: Endpoint>(PhantomData<E>);
+
+impl<E: Endpoint> ConnectionUri for Wss<E> {
+    fn get_server_uri() -> &'static str {
+        intern(format!("wss://{}", E::server()))
+    }
+}
+
+/// `ConnectionUri` hands out `&'static str`s, so every distinct URI is leaked once and reused on
+/// reconnects.
+fn intern(uri: String) -> &'static str {
+    static URIS: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+    let mut uris = URIS.lock().unwrap();
+    if let Some(known) = uris.iter().find(|known| **known == uri) {
+        return known;
+    }
+    let uri: &'static str = Box::leak(uri.into_boxed_str());
+    uris.push(uri);
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_irc::{FakeClient, FakeServer};
+    use twitch_irc::{login::StaticLoginCredentials, ClientConfig};
+
+    #[test]
+    fn parses_servers() {
+        assert_eq!(
+            parse_server("localhost:6667"),
+            Ok(Server {
+                host: "localhost".to_owned(),
+                port: 6667
+            })
+        );
+        assert_eq!(parse_server("::1:6667").unwrap().host, "::1");
+        assert_eq!(parse_server("[::1]:6667").unwrap().host, "::1");
+        assert!(parse_server("[]:6667").is_err());
+        assert!(parse_server("localhost").is_err());
+        assert!(parse_server(":6667").is_err());
+        assert!(parse_server("localhost:ircd").is_err());
+    }
+
+    #[tokio::test]
+    async fn connects_to_the_server_of_its_endpoint() {
+        let mut server = FakeServer::start().await;
+        server.use_for_this_thread();
+        let (_, client) =
+            FakeClient::new(ClientConfig::new_simple(StaticLoginCredentials::anonymous()));
+
+        client.join("marbles".to_owned()).unwrap();
+
+        let mut connection = server.accept().await;
+        connection.handshake().await;
+        connection.join("marbles").await;
+    }
+
+    struct Ipv6Endpoint;
+
+    impl Endpoint for Ipv6Endpoint {
+        fn server() -> Server {
+            parse_server("::1:8080").unwrap()
+        }
+    }
+
+    #[test]
+    fn brackets_ipv6_hosts_in_websocket_uris() {
+        assert_eq!(Ws::<Ipv6Endpoint>::get_server_uri(), "ws://[::1]:8080");
+        assert_eq!(Wss::<Ipv6Endpoint>::get_server_uri(), "wss://[::1]:8080");
+    }
+
+    #[test]
+    fn interns_websocket_uris() {
+        let uri = intern("ws://localhost:8080".to_owned());
+        assert!(std::ptr::eq(uri, intern("ws://localhost:8080".to_owned())));
+        assert_ne!(uri, intern("wss://localhost:8080".to_owned()));
+    }
+}
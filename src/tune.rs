@@ -0,0 +1,316 @@
+//! Grid-searches the trigger settings over recorded chat, scoring every combination against the
+//! times races actually took place.
+//!
+//! Races are given as JSON lines like
+//! `{"channel": "marbles", "start": "2023-05-01T20:00:00Z", "end": "2023-05-01T20:01:30Z"}`,
+//! from the moment the lobby opens to the moment it stops taking players.
+
+use crate::chat::ChatMessage;
+use crate::clock::Clock;
+use crate::config::{normalize_channel, ChannelConfig, Config, TriggerMode};
+use crate::metrics::Metrics;
+use crate::replay::{self, ReplayError, ReplayedPlay};
+use crate::ChannelFactory;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Race {
+    pub channel: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl Race {
+    fn contains(self: &Race, time: DateTime<Utc>) -> bool {
+        self.start <= time && time <= self.end
+    }
+}
+
+pub fn read_races(path: &Path) -> Result<Vec<Race>, ReplayError> {
+    let contents = fs::read_to_string(path).map_err(|error| ReplayError::Io(path.into(), error))?;
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            let race: Race = serde_json::from_str(line)
+                .map_err(|error| ReplayError::Parse(path.into(), index + 1, error.to_string()))?;
+            Ok(Race {
+                channel: normalize_channel(&race.channel),
+                ..race
+            })
+        })
+        .collect()
+}
+
+/// One combination of the grid. The buffer size only matters in the buffer trigger mode and the
+/// window only in the window trigger mode, so each combination sets just the one it uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub trigger_mode: TriggerMode,
+    pub buffer_size: Option<usize>,
+    pub window: Option<u64>,
+    pub treshhold: usize,
+}
+
+impl fmt::Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.buffer_size, self.window) {
+            (Some(buffer_size), _) => write!(f, "buffer_size {:>3}", buffer_size)?,
+            (_, Some(window)) => write!(f, "window {:>3}s    ", window)?,
+            (None, None) => write!(f, "               ")?,
+        }
+        write!(f, " treshhold {:>2}", self.treshhold)
+    }
+}
+
+/// The values to try for every setting.
+#[derive(Debug, Clone)]
+pub struct Grid {
+    pub trigger_modes: Vec<TriggerMode>,
+    pub buffer_sizes: Vec<usize>,
+    pub windows: Vec<u64>,
+    pub treshholds: Vec<usize>,
+}
+
+impl Grid {
+    pub fn settings(self: &Grid) -> Vec<Settings> {
+        let mut settings = Vec::new();
+        for &trigger_mode in self.trigger_modes.iter() {
+            let sizes: Vec<(Option<usize>, Option<u64>)> = match trigger_mode {
+                TriggerMode::Buffer => self.buffer_sizes.iter().map(|&b| (Some(b), None)).collect(),
+                TriggerMode::Window => self.windows.iter().map(|&w| (None, Some(w))).collect(),
+            };
+            for (buffer_size, window) in sizes {
+                for &treshhold in self.treshholds.iter() {
+                    settings.push(Settings {
+                        trigger_mode,
+                        buffer_size,
+                        window,
+                        treshhold,
+                    });
+                }
+            }
+        }
+        settings
+    }
+}
+
+/// How one combination did in one channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Score {
+    pub settings: Settings,
+    pub plays: usize,
+    /// Plays sent while a race was open.
+    pub plays_in_race: usize,
+    /// Races with at least one play sent while they were open.
+    pub races_joined: usize,
+    pub races: usize,
+}
+
+impl Score {
+    fn new(settings: Settings, plays: &[&ReplayedPlay], races: &[&Race]) -> Score {
+        Score {
+            settings,
+            plays: plays.len(),
+            plays_in_race: plays
+                .iter()
+                .filter(|play| races.iter().any(|race| race.contains(play.sent_at)))
+                .count(),
+            races_joined: races
+                .iter()
+                .filter(|race| plays.iter().any(|play| race.contains(play.sent_at)))
+                .count(),
+            races: races.len(),
+        }
+    }
+
+    /// Share of plays that landed in a race, zero if there were none at all.
+    pub fn precision(self: &Score) -> f64 {
+        if self.plays == 0 {
+            0.0
+        } else {
+            self.plays_in_race as f64 / self.plays as f64
+        }
+    }
+
+    /// Share of races that were joined.
+    pub fn recall(self: &Score) -> f64 {
+        if self.races == 0 {
+            0.0
+        } else {
+            self.races_joined as f64 / self.races as f64
+        }
+    }
+
+    fn dominates(self: &Score, other: &Score) -> bool {
+        self.precision() >= other.precision()
+            && self.recall() >= other.recall()
+            && (self.precision() > other.precision() || self.recall() > other.recall())
+    }
+}
+
+impl fmt::Display for Score {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:>4} plays, precision {:.2}, recall {:.2} ({}/{} races)",
+            self.settings,
+            self.plays,
+            self.precision(),
+            self.recall(),
+            self.races_joined,
+            self.races
+        )
+    }
+}
+
+/// Whether each score is Pareto-best, i.e. no other score beats it on both precision and recall.
+pub fn pareto_front(scores: &[Score]) -> Vec<bool> {
+    scores
+        .iter()
+        .map(|score| !scores.iter().any(|other| other.dominates(score)))
+        .collect()
+}
+
+/// Replays `messages` once per combination of `grid` on top of the configured settings and scores
+/// the plays of every channel that has races against them.
+pub fn tune(
+    messages: &[ChatMessage],
+    races: &[Race],
+    config: &Config,
+    overrides: &ChannelConfig,
+    grid: &Grid,
+) -> Result<BTreeMap<String, Vec<Score>>, ReplayError> {
+    let mut races_by_channel: BTreeMap<String, Vec<&Race>> = BTreeMap::new();
+    for race in races {
+        races_by_channel
+            .entry(race.channel.to_owned())
+            .or_default()
+            .push(race);
+    }
+
+    let mut scores: BTreeMap<String, Vec<Score>> = BTreeMap::new();
+    for settings in grid.settings() {
+        let channel_factory = ChannelFactory {
+            config: config.clone(),
+            overrides: ChannelConfig {
+                trigger_mode: Some(settings.trigger_mode),
+                buffer_size: settings.buffer_size.or(overrides.buffer_size),
+                window: settings.window.or(overrides.window),
+                treshhold: Some(settings.treshhold),
+                ..overrides.clone()
+            },
+            login: String::new(),
+            metrics: Metrics::new(),
+            clock: Clock::new_virtual(),
+        };
+        // hundreds of replays logging every trigger would drown the report
+        let plays = tracing::dispatcher::with_default(&tracing::Dispatch::none(), || {
+            replay::replay(messages, &channel_factory)
+        })?;
+        for (channel, races) in races_by_channel.iter() {
+            let channel_plays: Vec<&ReplayedPlay> = plays
+                .iter()
+                .filter(|play| &play.channel == channel)
+                .collect();
+            scores
+                .entry(channel.to_owned())
+                .or_default()
+                .push(Score::new(settings, &channel_plays, races));
+        }
+    }
+    Ok(scores)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(second: u32) -> DateTime<Utc> {
+        format!("2023-05-01T20:00:{:02}Z", second).parse().unwrap()
+    }
+
+    fn settings(treshhold: usize) -> Settings {
+        Settings {
+            trigger_mode: TriggerMode::Buffer,
+            buffer_size: Some(10),
+            window: None,
+            treshhold,
+        }
+    }
+
+    fn score(treshhold: usize, plays: usize, plays_in_race: usize, races_joined: usize) -> Score {
+        Score {
+            settings: settings(treshhold),
+            plays,
+            plays_in_race,
+            races_joined,
+            races: 4,
+        }
+    }
+
+    #[test]
+    fn scores_plays_against_races() {
+        let race = |start, end| Race {
+            channel: "marbles".to_owned(),
+            start: time(start),
+            end: time(end),
+        };
+        let play = |second| ReplayedPlay {
+            channel: "marbles".to_owned(),
+            triggered_at: time(second),
+            pattern: None,
+            sent_at: time(second),
+        };
+        let races = [race(0, 10), race(20, 30), race(40, 50)];
+        let plays = [play(5), play(8), play(15), play(45)];
+
+        let score = Score::new(
+            settings(5),
+            &plays.iter().collect::<Vec<_>>(),
+            &races.iter().collect::<Vec<_>>(),
+        );
+
+        assert_eq!(score.plays_in_race, 3);
+        assert_eq!(score.races_joined, 2);
+        assert_eq!(score.precision(), 0.75);
+        assert_eq!(score.recall(), 2.0 / 3.0);
+    }
+
+    #[test]
+    fn finds_pareto_front() {
+        let scores = [
+            score(2, 10, 5, 4),
+            score(3, 6, 5, 3),
+            score(4, 6, 4, 3),
+            score(5, 3, 3, 2),
+            score(6, 0, 0, 0),
+        ];
+
+        assert_eq!(pareto_front(&scores), [true, true, false, true, false]);
+    }
+
+    #[test]
+    fn buffer_size_and_window_only_vary_in_their_mode() {
+        let grid = Grid {
+            trigger_modes: vec![TriggerMode::Buffer, TriggerMode::Window],
+            buffer_sizes: vec![5, 10],
+            windows: vec![30],
+            treshholds: vec![2, 3],
+        };
+
+        let settings = grid.settings();
+
+        assert_eq!(settings.len(), 6);
+        assert!(settings
+            .iter()
+            .all(|settings| settings.buffer_size.is_some() != settings.window.is_some()));
+    }
+}